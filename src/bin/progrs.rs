@@ -1,6 +1,89 @@
-use std::io;
+use std::{env, io, path::PathBuf};
+
+use chrono::NaiveDate;
+use progrs::index::Filter;
+
+const USAGE: &str = "Usage:
+  progrs                    Watch the log directory and record
+  progrs replay <logfile> [--speed <factor>] [--record]
+                            Stream a finished log through the parser and print
+                            the events. --speed replays in real time scaled by
+                            factor, --record handles the events like live mode
+  progrs list [--boss <name>] [--difficulty <difficulty>] [--from <date>]
+              [--to <date>] [--outcome <outcome>] [--key <level>]
+                            List the recordings, dates as YYYY-MM-DD, outcomes
+                            like kill, wipe, timed, depleted, win or loss";
 
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
-  progrs::main().await
+  let args: Vec<String> = env::args().skip(1).collect();
+
+  match args.first().map(String::as_str) {
+    None => progrs::main().await,
+    Some("replay") => {
+      let mut logfile = None;
+      let mut speed = None;
+      let mut record = false;
+
+      let mut it = args[1..].iter();
+      while let Some(arg) = it.next() {
+        match arg.as_str() {
+          "--speed" => {
+            let factor = it.next().and_then(|s| s.parse::<f64>().ok());
+            match factor {
+              Some(f) if f > 0.0 => speed = Some(f),
+              _ => return usage("--speed needs a positive number"),
+            }
+          }
+          "--record" => record = true,
+          _ if logfile.is_none() => logfile = Some(PathBuf::from(arg)),
+          _ => return usage(&format!("Unexpected argument '{arg}'")),
+        }
+      }
+
+      let Some(logfile) = logfile else {
+        return usage("Missing logfile");
+      };
+
+      progrs::replay(&logfile, speed, record).await
+    }
+    Some("list") => {
+      let mut filter = Filter::default();
+
+      let mut it = args[1..].iter();
+      while let Some(arg) = it.next() {
+        let Some(value) = it.next() else {
+          return usage(&format!("{arg} needs a value"));
+        };
+        let date = || NaiveDate::parse_from_str(value, "%Y-%m-%d").ok();
+
+        match arg.as_str() {
+          "--boss" => filter.boss = Some(value.clone()),
+          "--difficulty" => filter.difficulty = Some(value.clone()),
+          "--from" => match date() {
+            Some(d) => filter.from = Some(d),
+            None => return usage("--from needs a date like 2024-09-19"),
+          },
+          "--to" => match date() {
+            Some(d) => filter.to = Some(d),
+            None => return usage("--to needs a date like 2024-09-19"),
+          },
+          "--outcome" => filter.outcome = Some(value.clone()),
+          "--key" => match value.parse() {
+            Ok(level) => filter.keystone_level = Some(level),
+            Err(_) => return usage("--key needs a keystone level"),
+          },
+          _ => return usage(&format!("Unexpected argument '{arg}'")),
+        }
+      }
+
+      progrs::list(&filter)
+    }
+    Some(arg) => usage(&format!("Unknown command '{arg}'")),
+  }
+}
+
+fn usage(error: &str) -> Result<(), io::Error> {
+  eprintln!("Error: {error}\n\n{USAGE}");
+  Err(io::Error::other("Invalid arguments"))
 }
@@ -2,6 +2,9 @@ use std::path::PathBuf;
 use std::os::unix::fs::PermissionsExt;
 
 use confique::Config;
+use serde::Deserialize;
+
+use crate::events::InstanceType;
 
 #[derive(Config)]
 pub struct ProgrsConfig {
@@ -15,19 +18,58 @@ pub struct ProgrsConfig {
   /// system.
   #[config(nested)]
   pub recorder: RecorderConfig,
-  /// The path to mkvmerge. This is used to merge chapter markers into the
-  /// video, for now deaths of players are supported. Assumes `mkvmerge` can
-  /// handle the output format of the configured recorder for this. If you don't
-  /// want/need this, simply put an empty string here.
+  /// Connection to obs-websocket, used with the "obs" recorder backend
+  #[config(nested)]
+  pub obs: ObsConfig,
+  /// Seconds to keep recording after an activity ended, e.g. to catch the
+  /// kill and the loot. Cut short if the next activity starts. Dungeons,
+  /// delves and battlegrounds end by leaving them, so there is nothing to
+  /// catch.
+  #[config(nested)]
+  pub post_roll_seconds: PostRollConfig,
+  /// Automatic deletion of old recordings in `viddir` after each recording,
+  /// together with their sidecars, and the free space needed for recording.
+  /// Nothing is deleted by default.
+  #[config(nested)]
+  pub retention: RetentionConfig,
+  /// The path to mkvmerge. This is used to merge chapter markers and a title
+  /// (e.g. the affixes of a key) into the video, for now deaths of players and
+  /// boss encounters are supported. Only used for Matroska recordings, mp4 and
+  /// mov recordings (as set in the recorder's `args`) get their chapters
+  /// merged by `recorder.ffmpeg`. If you don't want/need this, simply put an
+  /// empty string here.
   #[config(default = "/usr/bin/mkvmerge")]
   pub mkvmerge: String,
+  /// The format of the chapters merged into the video. "simple" chapters are
+  /// a flat list of points in time. "xml" are Matroska XML chapters with end
+  /// times, deaths during a boss encounter of a dungeon are nested below the
+  /// encounter's chapter. Only applies to Matroska, mp4 and mov recordings
+  /// get flat chapters with end times.
+  #[config(default = "simple")]
+  pub chapter_format: ChapterFormat,
+  /// The language of the chapter names in "xml" chapters, as ISO 639-2 code
+  #[config(default = "eng")]
+  pub chapter_language: String,
+  /// Instance types that are recorded as a whole, from entering until leaving
+  /// them, with a chapter per boss encounter. Possible values are "dungeon"
+  /// (normal, heroic, mythic, timewalking and follower dungeons) and "delve".
+  /// Mythic+ keys are recorded anyways, a dungeon recording running when the
+  /// key starts is stopped.
+  #[config(default = [])]
+  pub instance_types: Vec<InstanceType>,
 }
 
 #[derive(Config)]
 pub struct RecorderConfig {
+  /// Which recorder `command` is, this determines how the output file is
+  /// passed and how recording is stopped. One of "gpu-screen-recorder",
+  /// "ffmpeg" (output file last, stopped by sending `q`), "wf-recorder" and
+  /// "obs", which remote controls a running OBS as configured in `[obs]` and
+  /// ignores `command`, `args` and `outputswitch`.
+  #[config(default = "gpu-screen-recorder")]
+  pub backend: Backend,
   /// Full path of the binary to call
-  #[config(default = "/usr/bin/gpu-screen-recorder",
-    validate = executable)]
+  #[config(default = "/usr/bin/gpu-screen-recorder")]
   pub command: String,
   /// Arguments to use for recording. Array of Strings, which are passed to the
   /// binary in order. Skip the switch that designates the output file, that
@@ -48,8 +90,189 @@ pub struct RecorderConfig {
   pub args: Vec<String>,
   /// The command line switch to designate the output file. If the output file
   /// is the last argument without a switch, simply put an empty string here.
-  #[config(default = "-o")]
-  pub outputswitch: String
+  /// If unset, the convention of `backend` is used.
+  pub outputswitch: Option<String>,
+  /// The recorder's messages are logged to a file per recording (per start
+  /// of the replay buffer), in the `logs` directory of progrs' data directory
+  /// (e.g. ~/.local/share/progrs/logs). Lines containing any of these are left
+  /// out.
+  /// If unset, the default of `backend` is used, which skips the fps updates
+  /// of gpu-screen-recorder.
+  pub stderr_filter: Option<Vec<String>>,
+  /// Replay buffer mode, only for gpu-screen-recorder: if set, the recorder
+  /// runs all the time with a replay buffer (`-r`) of this many seconds, and
+  /// saves the buffer when an activity ends. This catches the pull, which is
+  /// otherwise often lost to the startup of the recorder. Needs to be longer
+  /// than the activities you want to record, plus `pre_roll_seconds`.
+  pub replay_buffer_seconds: Option<u32>,
+  /// In replay buffer mode, how many seconds before the start of an activity
+  /// are kept
+  #[config(default = 10)]
+  pub pre_roll_seconds: u32,
+  /// The path to ffmpeg, used to trim the saved replay buffer to the
+  /// activity (without it, the whole buffer is kept), and to merge chapters
+  /// and a title into mp4 and mov recordings.
+  #[config(default = "/usr/bin/ffmpeg")]
+  pub ffmpeg: String,
+  /// Milliseconds added to all chapter times. Chapters are aligned to when
+  /// the recorder started capturing, as far as progrs can tell (when it
+  /// started writing the file, or OBS confirmed the start). If they are still
+  /// off, correct that here, negative values move chapters earlier.
+  #[config(default = 0)]
+  pub chapter_correction_ms: i64,
+}
+
+impl RecorderConfig {
+  /// Checks that `command` can be run, unless `backend` ignores it
+  pub fn validate(&self) -> Result<(), &'static str> {
+    match self.backend {
+      Backend::Obs => Ok(()),
+      _ => executable(&self.command),
+    }
+  }
+
+  /// The container the recorder writes, as set in `args`. Matroska, unless
+  /// mp4 or mov are set explicitly. OBS picks the container itself, it is
+  /// known once the recording is stopped.
+  pub fn container(&self) -> Container {
+    let format = match self.backend {
+      Backend::GpuScreenRecorder => self.arg_value(&["-c"]),
+      // The output format follows the last input
+      Backend::Ffmpeg => {
+        let inputs = self.args.iter().rposition(|a| a == "-i");
+        let output = &self.args[inputs.map_or(0, |i| i + 1)..];
+        output
+          .iter()
+          .rposition(|a| a == "-f")
+          .and_then(|i| output.get(i + 1))
+          .map(String::as_str)
+      }
+      Backend::WfRecorder => self.arg_value(&["-m", "--muxer"]),
+      Backend::Obs => None,
+    };
+
+    match format {
+      Some("mp4") => Container::Mp4,
+      Some("mov") => Container::Mov,
+      _ => Container::Matroska,
+    }
+  }
+
+  /// The value following the last of the `switches` in `args`, also accepts
+  /// `--switch=value`
+  fn arg_value(&self, switches: &[&str]) -> Option<&str> {
+    let mut value = None;
+    for (idx, arg) in self.args.iter().enumerate() {
+      if switches.contains(&arg.as_str()) {
+        value = self.args.get(idx + 1).map(String::as_str);
+      } else if let Some((switch, v)) = arg.split_once('=') {
+        if switches.contains(&switch) {
+          value = Some(v);
+        }
+      }
+    }
+    value
+  }
+}
+
+#[derive(Config)]
+pub struct PostRollConfig {
+  /// After a boss kill or wipe
+  #[config(default = 5)]
+  pub raid: u32,
+  /// After a Mythic+ key, timed or not
+  #[config(default = 10)]
+  pub mythicplus: u32,
+  /// After an arena match or Solo Shuffle
+  #[config(default = 5)]
+  pub arena: u32,
+}
+
+#[derive(Config)]
+pub struct RetentionConfig {
+  /// Maximum total size of the recordings in GiB. The oldest are deleted
+  /// once it is exceeded.
+  pub max_size_gib: Option<u64>,
+  /// Recordings older than this many days are deleted
+  pub max_age_days: Option<u32>,
+  /// Never delete boss kills
+  #[config(default = false)]
+  pub keep_kills: bool,
+  /// Never delete timed keys
+  #[config(default = false)]
+  pub keep_timed_keys: bool,
+  /// File names of recordings that are never deleted, with or without
+  /// extension
+  #[config(default = [])]
+  pub favorites: Vec<String>,
+  /// Recording is skipped if less than this many GiB are free in `viddir`,
+  /// the file would likely end up corrupt
+  #[config(default = 2)]
+  pub min_free_gib: u64,
+  /// If too little space is free for a recording, delete the oldest
+  /// recordings to make room, regardless of `max_size_gib` and
+  /// `max_age_days`. Kills, timed keys and favorites are still kept as
+  /// configured.
+  #[config(default = false)]
+  pub emergency_cleanup: bool,
+}
+
+#[derive(Config)]
+pub struct ObsConfig {
+  /// Host and port of obs-websocket, see Tools -> WebSocket Server Settings in
+  /// OBS. OBS has to record into mkv, mp4 or mov, the file is moved into
+  /// `viddir` after recording.
+  #[config(default = "localhost:4455")]
+  pub address: String,
+  /// The password for obs-websocket, if authentication is enabled
+  pub password: Option<String>,
+  /// Whether to create chapter markers in OBS as things happen. Needs OBS 30.2
+  /// or newer recording in Hybrid MP4, the chapters are merged into the
+  /// finished file anyways.
+  #[config(default = false)]
+  pub markers: bool,
+}
+
+/// The recorders progrs knows how to handle
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+  GpuScreenRecorder,
+  Ffmpeg,
+  WfRecorder,
+  Obs,
+}
+
+/// The chapter formats mkvmerge understands
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChapterFormat {
+  Simple,
+  Xml,
+}
+
+/// The containers progrs can merge chapters into
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Container {
+  Matroska,
+  Mp4,
+  Mov,
+}
+
+impl Container {
+  pub const ALL: [Self; 3] = [Self::Matroska, Self::Mp4, Self::Mov];
+
+  pub fn from_extension(extension: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|c| c.extension() == extension)
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Self::Matroska => "mkv",
+      Self::Mp4 => "mp4",
+      Self::Mov => "mov",
+    }
+  }
 }
 
 pub fn executable(s: &String) -> Result<(), &'static str> {
@@ -1,16 +1,272 @@
-use std::io;
+use std::{fmt::Display, io, path::PathBuf, time::Duration};
 
 use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::parser::ParseError;
 
 #[derive(Debug)]
 pub enum Event {
-  EncounterStart(NaiveDateTime, String),
-  EncounterEnd,
+  EncounterStart(NaiveDateTime, Encounter),
+  EncounterEnd(NaiveDateTime, EncounterResult),
   PlayerDeath(NaiveDateTime, String),
-  ChallengeModeStart(NaiveDateTime, String),
-  ChallengeModeEnd,
+  ChallengeModeStart(NaiveDateTime, ChallengeMode),
+  ChallengeModeEnd(NaiveDateTime, ChallengeModeResult),
+  ArenaMatchStart(NaiveDateTime, ArenaMatch),
+  ArenaMatchEnd(NaiveDateTime, ArenaResult),
+  ZoneChange(NaiveDateTime, Zone),
+  CombatantInfo(NaiveDateTime, Combatant),
+  // A line of the log could not be parsed and was skipped
+  ParseWarning(ParseError),
   //  NewFile(PathBuf),
+  // The whole file was replayed
+  ReplayFinished,
+  // A recording was stopped and post-processed into this file
+  RecordingSaved(PathBuf),
+  // Stopping or post-processing the recording with this file name failed
+  RecordingFailed(String, io::Error),
+  // An activity was not recorded, for this reason
+  RecordingSkipped(String),
+  // An activity is recorded despite this problem
+  RecordingWarning(String),
+  // The recorder stopped during a recording, for this reason
+  RecorderDied(String),
   IoErr(io::Error),
   // Ctrl-C was pressed
   CtrlC,
 }
+
+impl Event {
+  /// Whether this event starts something that might be recorded. Zones only
+  /// do if they are battlegrounds or one of the recorded `instance_types`.
+  pub fn starts_activity(&self, instance_types: &[InstanceType]) -> bool {
+    match self {
+      Event::EncounterStart(..)
+      | Event::ChallengeModeStart(..)
+      | Event::ArenaMatchStart(..) => true,
+      Event::ZoneChange(_, zone) => {
+        zone.is_battleground()
+          || zone
+            .instance_type()
+            .is_some_and(|t| instance_types.contains(&t))
+      }
+      _ => false,
+    }
+  }
+}
+
+/// The data of an ENCOUNTER_START line
+#[derive(Clone, Debug, PartialEq)]
+pub struct Encounter {
+  pub id: u32,
+  pub name: String,
+  pub difficulty: Difficulty,
+  pub group_size: u32,
+  pub instance_id: u32,
+}
+
+#[cfg(test)]
+impl Encounter {
+  /// A Mythic raid boss, for tests
+  pub fn raid_fixture() -> Self {
+    Self {
+      id: 2922,
+      name: "Queen Ansurek".to_string(),
+      difficulty: Difficulty::Mythic,
+      group_size: 20,
+      instance_id: 2657,
+    }
+  }
+}
+
+/// The data of an ENCOUNTER_END line
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncounterResult {
+  pub id: u32,
+  /// `true` on a kill, `false` on a wipe
+  pub success: bool,
+  /// Length of the fight
+  pub duration: Duration,
+}
+
+/// The data of a CHALLENGE_MODE_START line
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChallengeMode {
+  /// Name of the dungeon
+  pub name: String,
+  pub map_id: u32,
+  pub challenge_mode_id: u32,
+  pub keystone_level: u32,
+  pub affixes: Vec<u32>,
+}
+
+/// The data of a CHALLENGE_MODE_END line
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChallengeModeResult {
+  pub map_id: u32,
+  /// `true` if the key was timed, `false` if it was depleted or abandoned
+  pub success: bool,
+  pub keystone_level: u32,
+  /// Total run time
+  pub duration: Duration,
+  /// Overall Mythic+ rating after the key, not present in older logs
+  pub rating: Option<f64>,
+  /// Change of the Mythic+ rating, not present in older logs
+  pub rating_change: Option<f64>,
+}
+
+/// The data of an ARENA_MATCH_START line
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArenaMatch {
+  pub instance_id: u32,
+  pub rated: bool,
+  /// E.g. `2v2`, `3v3`
+  pub bracket: String,
+  /// The team of the logging player
+  pub team_id: u32,
+}
+
+impl ArenaMatch {
+  /// Solo Shuffle has an ARENA_MATCH_START for every round, but only one
+  /// ARENA_MATCH_END
+  pub fn is_solo_shuffle(&self) -> bool {
+    self.bracket.contains("Solo Shuffle")
+  }
+}
+
+/// The data of an ARENA_MATCH_END line
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArenaResult {
+  pub winning_team: u32,
+  pub duration: Duration,
+  /// New ratings of team 0 and 1
+  pub ratings: [u32; 2],
+}
+
+/// The data of a COMBATANT_INFO line, logged for every participant at the
+/// start of an encounter, key or arena match
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Combatant {
+  /// The player GUID, e.g. `Player-1234-0ABCDEF0`
+  pub guid: String,
+  /// 0 for Horde, 1 for Alliance
+  pub faction: u32,
+  /// The specialization ID, 0 if unknown
+  pub spec_id: u32,
+}
+
+/// The data of a ZONE_CHANGE line
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+  pub instance_id: u32,
+  pub name: String,
+  /// The raw difficultyID, needed to tell dungeons from raids
+  pub difficulty_id: u32,
+}
+
+/// Kinds of instances that can be recorded from entering until leaving them
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceType {
+  /// Normal, heroic, mythic, timewalking and follower dungeons
+  Dungeon,
+  Delve,
+}
+
+/// Instance IDs of all battlegrounds, including their old/seasonal versions
+const BATTLEGROUNDS: &[u32] = &[
+  30,   // Alterac Valley
+  489,  // Warsong Gulch (classic)
+  529,  // Arathi Basin (classic)
+  566,  // Eye of the Storm
+  607,  // Strand of the Ancients
+  628,  // Isle of Conquest
+  726,  // Twin Peaks
+  727,  // Silvershard Mines
+  761,  // The Battle for Gilneas
+  968,  // Eye of the Storm (rated)
+  998,  // Temple of Kotmogu
+  1105, // Deepwind Gorge (classic)
+  1280, // Southshore vs. Tarren Mill
+  1681, // Arathi Basin (winter)
+  1803, // Seething Shore
+  2106, // Warsong Gulch
+  2107, // Arathi Basin
+  2118, // Battle for Wintergrasp
+  2177, // Arathi Basin (comp stomp)
+  2197, // Korrak's Revenge
+  2245, // Deepwind Gorge
+  2656, // Deephaul Ravine
+];
+
+impl Zone {
+  pub fn difficulty(&self) -> Difficulty {
+    self.difficulty_id.into()
+  }
+
+  pub fn is_battleground(&self) -> bool {
+    BATTLEGROUNDS.contains(&self.instance_id)
+  }
+
+  /// Returns the kind of instance this is, if it's one that can be recorded
+  /// as a whole
+  pub fn instance_type(&self) -> Option<InstanceType> {
+    match self.difficulty_id {
+      1 | 2 | 23 | 24 | 205 => Some(InstanceType::Dungeon),
+      208 => Some(InstanceType::Delve),
+      _ => None,
+    }
+  }
+}
+
+/// Difficulty of an encounter, mapped from the difficultyID in the log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Difficulty {
+  LookingForRaid,
+  Normal,
+  Heroic,
+  Mythic,
+  /// Mythic+ dungeon
+  MythicKeystone,
+  Timewalking,
+  Follower,
+  Delve,
+  Story,
+  /// Unknown difficultyID
+  Other(u32),
+}
+
+impl From<u32> for Difficulty {
+  fn from(id: u32) -> Self {
+    // See https://warcraft.wiki.gg/wiki/DifficultyID
+    match id {
+      7 | 17 => Self::LookingForRaid,
+      1 | 3 | 4 | 9 | 14 => Self::Normal,
+      2 | 5 | 6 | 15 => Self::Heroic,
+      16 | 23 => Self::Mythic,
+      8 => Self::MythicKeystone,
+      24 | 33 => Self::Timewalking,
+      205 => Self::Follower,
+      208 => Self::Delve,
+      220 => Self::Story,
+      id => Self::Other(id),
+    }
+  }
+}
+
+impl Display for Difficulty {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::LookingForRaid => write!(f, "LFR"),
+      Self::Normal => write!(f, "Normal"),
+      Self::Heroic => write!(f, "Heroic"),
+      Self::Mythic => write!(f, "Mythic"),
+      Self::MythicKeystone => write!(f, "Mythic+"),
+      Self::Timewalking => write!(f, "Timewalking"),
+      Self::Follower => write!(f, "Follower"),
+      Self::Delve => write!(f, "Delve"),
+      Self::Story => write!(f, "Story"),
+      Self::Other(id) => write!(f, "Difficulty{id}"),
+    }
+  }
+}
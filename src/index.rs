@@ -0,0 +1,332 @@
+use std::{
+  fmt::Display,
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use chrono::NaiveDate;
+use rusqlite::{params, params_from_iter, types::Value, Connection, Row};
+
+use crate::recorder::metadata::Metadata;
+
+/// The name of the index in the data directory
+pub const INDEX_FILE: &str = "recordings.sqlite";
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS recordings (
+    id INTEGER PRIMARY KEY,
+    file TEXT NOT NULL,
+    activity TEXT NOT NULL,
+    title TEXT NOT NULL,
+    name TEXT,
+    encounter_id INTEGER,
+    instance_id INTEGER NOT NULL,
+    difficulty TEXT,
+    keystone_level INTEGER,
+    outcome TEXT,
+    pull INTEGER,
+    start TEXT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    events TEXT NOT NULL,
+    -- Deleted recordings are kept to count the pulls
+    deleted INTEGER NOT NULL DEFAULT 0
+  );
+  CREATE INDEX IF NOT EXISTS recordings_start ON recordings (start);
+";
+
+/// An SQLite index of all finished recordings, to find them without going
+/// through the video directory
+pub struct Index {
+  conn: Connection,
+}
+
+/// A recording in the index
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+  pub file: PathBuf,
+  /// See `Activity::kind`
+  pub activity: String,
+  /// The boss, dungeon or zone
+  pub name: Option<String>,
+  pub difficulty: Option<String>,
+  pub keystone_level: Option<u32>,
+  pub outcome: Option<String>,
+  /// How many recordings of this boss on this difficulty there were up to
+  /// and including this one, only for raids
+  pub pull: Option<u32>,
+  /// Log time the activity started at
+  pub start: String,
+  pub duration_ms: i64,
+  /// The timeline events as JSON, like in the sidecar
+  pub events: String,
+}
+
+/// Restricts which recordings are listed, unset fields match everything
+#[derive(Debug, Default)]
+pub struct Filter {
+  /// Part of the name of the boss, dungeon or zone, case insensitive
+  pub boss: Option<String>,
+  pub difficulty: Option<String>,
+  /// First day to list
+  pub from: Option<NaiveDate>,
+  /// Last day to list
+  pub to: Option<NaiveDate>,
+  pub outcome: Option<String>,
+  pub keystone_level: Option<u32>,
+}
+
+impl Index {
+  /// Opens the index at `path`, creating it if it does not exist
+  pub fn open(path: &Path) -> io::Result<Self> {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+
+    let conn = Connection::open(path).map_err(io::Error::other)?;
+    conn.execute_batch(SCHEMA).map_err(io::Error::other)?;
+    Ok(Self { conn })
+  }
+
+  /// Adds the recording saved as `file`. Returns its pull number, if it is a
+  /// raid boss.
+  pub fn add(
+    &self,
+    file: &Path,
+    metadata: &Metadata,
+  ) -> io::Result<Option<u32>> {
+    let pull = match metadata.encounter_id {
+      Some(id) if metadata.activity == "raid" => {
+        let previous: u32 = self
+          .conn
+          .query_row(
+            "SELECT COUNT(*) FROM recordings
+             WHERE activity = 'raid' AND encounter_id = ?1
+               AND difficulty IS ?2",
+            params![id, metadata.difficulty],
+            |row| row.get(0),
+          )
+          .map_err(io::Error::other)?;
+        Some(previous + 1)
+      }
+      _ => None,
+    };
+
+    let events =
+      serde_json::to_string(&metadata.events).map_err(io::Error::other)?;
+    self
+      .conn
+      .execute(
+        "INSERT INTO recordings (file, activity, title, name, encounter_id,
+           instance_id, difficulty, keystone_level, outcome, pull, start,
+           duration_ms, events)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+          stored_path(file),
+          metadata.activity,
+          metadata.title,
+          metadata.name,
+          metadata.encounter_id,
+          metadata.instance_id,
+          metadata.difficulty,
+          metadata.keystone_level,
+          metadata.outcome,
+          pull,
+          metadata.start,
+          metadata.duration_ms,
+          events,
+        ],
+      )
+      .map_err(io::Error::other)?;
+    Ok(pull)
+  }
+
+  /// Removes the recording saved as `file` from the list. It still counts for
+  /// the pull numbers of later recordings. Fails if `file` is not indexed.
+  pub fn remove(&self, file: &Path) -> io::Result<()> {
+    let updated = self
+      .conn
+      .execute(
+        "UPDATE recordings SET deleted = 1 WHERE file = ?1 AND deleted = 0",
+        params![stored_path(file)],
+      )
+      .map_err(io::Error::other)?;
+    if updated == 0 {
+      return Err(io::Error::other("not indexed"));
+    }
+    Ok(())
+  }
+
+  /// The recordings matching `filter`, oldest first
+  pub fn list(&self, filter: &Filter) -> io::Result<Vec<Entry>> {
+    let mut conditions = vec!["deleted = 0"];
+    let mut values: Vec<Value> = vec![];
+
+    if let Some(boss) = &filter.boss {
+      conditions.push("name LIKE ?");
+      values.push(format!("%{boss}%").into());
+    }
+    if let Some(difficulty) = &filter.difficulty {
+      conditions.push("difficulty = ? COLLATE NOCASE");
+      values.push(difficulty.clone().into());
+    }
+    if let Some(from) = filter.from {
+      conditions.push("date(start) >= ?");
+      values.push(from.format("%Y-%m-%d").to_string().into());
+    }
+    if let Some(to) = filter.to {
+      conditions.push("date(start) <= ?");
+      values.push(to.format("%Y-%m-%d").to_string().into());
+    }
+    if let Some(outcome) = &filter.outcome {
+      conditions.push("outcome = ? COLLATE NOCASE");
+      values.push(outcome.clone().into());
+    }
+    if let Some(level) = filter.keystone_level {
+      conditions.push("keystone_level = ?");
+      values.push(level.into());
+    }
+
+    let mut query = "SELECT file, activity, name, difficulty, keystone_level,
+        outcome, pull, start, duration_ms, events
+      FROM recordings WHERE "
+      .to_string();
+    query += &conditions.join(" AND ");
+    query += " ORDER BY start, id";
+    let mut statement =
+      self.conn.prepare(&query).map_err(io::Error::other)?;
+    let entries = statement
+      .query_map(params_from_iter(values), entry_from_row)
+      .map_err(io::Error::other)?;
+    entries
+      .collect::<Result<_, _>>()
+      .map_err(io::Error::other)
+  }
+}
+
+/// `file` as stored in the index. Redundant separators are dropped, so the
+/// same file is found however its directory was written.
+fn stored_path(file: &Path) -> String {
+  let file: PathBuf = file.components().collect();
+  file.to_string_lossy().to_string()
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<Entry> {
+  Ok(Entry {
+    file: PathBuf::from(row.get::<_, String>(0)?),
+    activity: row.get(1)?,
+    name: row.get(2)?,
+    difficulty: row.get(3)?,
+    keystone_level: row.get(4)?,
+    outcome: row.get(5)?,
+    pull: row.get(6)?,
+    start: row.get(7)?,
+    duration_ms: row.get(8)?,
+    events: row.get(9)?,
+  })
+}
+
+/// One line per recording in `progrs list`
+impl Display for Entry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let start = self.start.get(..16).unwrap_or(&self.start);
+    write!(f, "{}  {:<12}", start.replace('T', " "), self.activity)?;
+
+    if let Some(level) = self.keystone_level {
+      write!(f, "  +{level}")?;
+    } else if let Some(difficulty) = &self.difficulty {
+      write!(f, "  {difficulty}")?;
+    }
+    if let Some(name) = &self.name {
+      write!(f, " {name}")?;
+    }
+    if let Some(pull) = self.pull {
+      write!(f, ", pull {pull}")?;
+    }
+    if let Some(outcome) = &self.outcome {
+      write!(f, ", {outcome}")?;
+    }
+
+    let seconds = self.duration_ms / 1000;
+    write!(
+      f,
+      " ({}:{:02})  {}",
+      seconds / 60,
+      seconds % 60,
+      self.file.display()
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+
+  fn raid(outcome: &str, start: &str) -> Metadata {
+    Metadata {
+      start: start.to_string(),
+      outcome: Some(outcome.to_string()),
+      ..Metadata::raid_fixture()
+    }
+  }
+
+  #[test]
+  fn pulls_and_filters() {
+    let path = env::temp_dir()
+      .join(format!("progrs-index-{}", std::process::id()))
+      .join(INDEX_FILE);
+    let _ = fs::remove_file(&path);
+    let index = Index::open(&path).unwrap();
+
+    let wipe = raid("wipe", "2024-09-19T20:14:04.000");
+    let kill = raid("kill", "2024-09-20T21:00:00.000");
+    assert_eq!(index.add(Path::new("a.mkv"), &wipe).unwrap(), Some(1));
+    assert_eq!(index.add(Path::new("b.mkv"), &kill).unwrap(), Some(2));
+
+    let all = index.list(&Filter::default()).unwrap();
+    assert_eq!(all.len(), 2);
+    assert_eq!(
+      all[1].to_string(),
+      "2024-09-20 21:00  raid          Mythic Queen Ansurek, pull 2, kill \
+       (5:12)  b.mkv"
+    );
+
+    let filter = Filter {
+      boss: Some("ansurek".to_string()),
+      difficulty: Some("mythic".to_string()),
+      outcome: Some("wipe".to_string()),
+      ..Filter::default()
+    };
+    let wipes = index.list(&filter).unwrap();
+    assert_eq!(wipes.len(), 1);
+    assert_eq!(wipes[0].file, Path::new("a.mkv"));
+
+    let filter = Filter {
+      from: NaiveDate::from_ymd_opt(2024, 9, 20),
+      to: NaiveDate::from_ymd_opt(2024, 9, 20),
+      ..Filter::default()
+    };
+    assert_eq!(index.list(&filter).unwrap()[0].pull, Some(2));
+
+    let filter = Filter {
+      keystone_level: Some(10),
+      ..Filter::default()
+    };
+    assert!(index.list(&filter).unwrap().is_empty());
+
+    // Deleted recordings still count as pulls
+    index.remove(Path::new("a.mkv")).unwrap();
+    index.remove(Path::new("b.mkv")).unwrap();
+    assert!(index.remove(Path::new("b.mkv")).is_err());
+    assert!(index.remove(Path::new("unknown.mkv")).is_err());
+    assert!(index.list(&Filter::default()).unwrap().is_empty());
+    assert_eq!(index.add(Path::new("c.mkv"), &wipe).unwrap(), Some(3));
+
+    // E.g. from a `viddir` with a trailing slash
+    index.add(Path::new("videos//d.mkv"), &kill).unwrap();
+    index.remove(Path::new("videos/d.mkv")).unwrap();
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+}
@@ -2,33 +2,102 @@ use std::{
   error::Error,
   fs::{self, create_dir_all},
   io,
-  path::Path,
+  path::{Path, PathBuf},
+  time::Duration,
 };
 
 use config::ProgrsConfig;
 use confique::{toml::template, toml::FormatOptions, Config};
-use ctrlc;
 use directories::ProjectDirs;
 use dirwatcher::DirWatcher;
-use recorder::{Activity, Recorder};
+use events::Event;
+use index::{Filter, Index, INDEX_FILE};
+use recorder::{
+  backend, postroll::PostRoll, Activity, Recorder};
+use tokio::{
+  sync::mpsc::{Receiver, Sender},
+  time::{interval, MissedTickBehavior},
+};
 
 const PREFIX: &[u8] = b"WoWCombatLog-";
+/// How often the recorder is checked for crashes during a recording
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
 
 pub mod config;
 //pub mod follow;
 pub mod dirwatcher;
 pub mod events;
+pub mod index;
 pub mod parser;
 pub mod recorder;
+pub mod replay;
 
+/// Watches the log directory and records activities as they happen
 pub async fn main() -> Result<(), io::Error> {
+  let Some(conf) = load_config()? else {
+    return Ok(());
+  };
+
+  let (dirwatcher, tx) = DirWatcher::at(&conf.watchdir)?;
+  set_ctrlc_handler(tx.clone());
+
+  run(conf, dirwatcher, tx).await
+}
+
+/// Streams `logfile` through the parser. With `speed`, the log's timestamps
+/// are honored, scaled by `speed` (i.e. 1.0 is real time). Without `record`,
+/// the events are just printed, otherwise they are handled like in live mode.
+pub async fn replay(
+  logfile: &Path,
+  speed: Option<f64>,
+  record: bool,
+) -> Result<(), io::Error> {
+  if !record {
+    let (mut replay, tx) = replay::at(logfile, speed)?;
+    drop(tx);
+
+    while let Some(e) = replay.recv().await {
+      println!("Event: '{e:?}'");
+    }
+    return Ok(());
+  }
+
+  let Some(conf) = load_config()? else {
+    return Ok(());
+  };
+
+  let (replay, tx) = replay::at(logfile, speed)?;
+  set_ctrlc_handler(tx.clone());
+
+  run(conf, replay, tx).await
+}
+
+/// Prints the recordings in the index matching `filter`
+pub fn list(filter: &Filter) -> Result<(), io::Error> {
+  let Some(path) = data_dir().map(|d| d.join(INDEX_FILE)) else {
+    return Err(io::Error::other("Could not determine data directory"));
+  };
+  if !path.exists() {
+    println!("No recordings indexed yet");
+    return Ok(());
+  }
+
+  for entry in Index::open(&path)?.list(filter)? {
+    println!("{entry}");
+  }
+  Ok(())
+}
+
+/// Reads the config file. Creates a default one and returns `None` if it does
+/// not exist yet.
+fn load_config() -> Result<Option<ProgrsConfig>, io::Error> {
   let Some(dirs) = ProjectDirs::from("", "", "progrs") else {
     return Err(io::Error::other(
       "Could not determine config directory, exiting",
     ));
   };
   let confdir = dirs.config_dir();
-  if create_dir_all(&confdir).is_err() {
+  if create_dir_all(confdir).is_err() {
     return Err(io::Error::other(
       "Could not create config directory, exiting",
     ));
@@ -45,11 +114,17 @@ pub async fn main() -> Result<(), io::Error> {
 
     let toml = template::<ProgrsConfig>(FormatOptions::default());
     fs::write(&conffile, &toml)?;
-    return Ok(());
+    return Ok(None);
   };
 
-  let conf = match ProgrsConfig::from_file(&conffile) {
-    Ok(c) => c,
+  match ProgrsConfig::from_file(&conffile) {
+    Ok(c) => {
+      if let Err(e) = c.recorder.validate() {
+        eprintln!("Error: recorder.command '{}': {e}", c.recorder.command);
+        return Err(io::Error::other("Error reading config file"));
+      }
+      Ok(Some(c))
+    }
     Err(e) => {
       eprintln!("Error: {e}");
 
@@ -58,76 +133,124 @@ pub async fn main() -> Result<(), io::Error> {
         eprintln!("Because of: {err}");
         e = err;
       }
-      return Err(io::Error::other("Error reading config file"));
+      Err(io::Error::other("Error reading config file"))
     }
-  };
+  }
+}
 
-  let mut recorder = Recorder::new(
-    conf.viddir,
-    conf.recorder.command,
-    conf.recorder.args,
-    conf.mkvmerge,
-  );
-  let (mut dirwatcher, tx) = DirWatcher::at(&conf.watchdir)?;
+/// The directory for progrs' own data, like logs
+fn data_dir() -> Option<PathBuf> {
+  ProjectDirs::from("", "", "progrs").map(|d| d.data_dir().to_path_buf())
+}
 
+fn set_ctrlc_handler(tx: Sender<Event>) {
   ctrlc::set_handler(move || {
     tx.blocking_send(events::Event::CtrlC)
       .expect("Ctrl-C channel");
   })
   .expect("Ctrl-C handler");
+}
+
+/// The main event loop, handles the events from `rx` until Ctrl-C is hit with
+/// no recording running. `tx` is the sending side of `rx`.
+async fn run(
+  conf: ProgrsConfig,
+  mut rx: Receiver<Event>,
+  tx: Sender<Event>,
+) -> Result<(), io::Error> {
+  let logdir = data_dir().map(|d| d.join("logs"));
+  let index = data_dir().map(|d| d.join(INDEX_FILE));
+  let backend = backend::create(&conf, logdir);
+  let mut recorder = Recorder::new(&conf, backend, index, tx);
+  // Ctrl-C was hit, exit once all recordings are processed
+  let mut exiting = false;
 
-  while let Some(e) = dirwatcher.recv().await {
+  let mut post_roll = PostRoll::default();
+  let mut health = interval(HEALTH_INTERVAL);
+  health.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+  loop {
+    let e = tokio::select! {
+      e = rx.recv() => match e {
+        Some(e) => e,
+        None => break,
+      },
+      () = post_roll.expired(), if post_roll.is_running() => {
+        recorder.stop_recording();
+        continue;
+      }
+      _ = health.tick(), if recorder.recording.is_some() => {
+        match recorder.check_health() {
+          Some(reason) => Event::RecorderDied(reason),
+          None => continue,
+        }
+      }
+    };
     println!("Event: '{e:?}'");
 
+    if post_roll.cut_short(&e, &conf.instance_types) {
+      println!("Next activity started, cutting the post-roll short");
+      recorder.stop_recording();
+    }
+    if let Some(recording) =
+      recorder.recording.as_ref().filter(|r| r.ended_by(&e))
+    {
+      println!("{} is over, stopping recording", recording.activity);
+      recorder.stop_recording();
+    }
+
     {
       use events::Event::*;
       match e {
-        EncounterStart(datetime, name) => {
+        EncounterStart(datetime, encounter) => {
+          match recorder.recording.as_ref() {
+            None => {
+              recorder.start_recording(datetime, Activity::Raid(encounter));
+            }
+            Some(recording) if recording.has_encounters() => {
+              recorder.add_encounter(datetime, encounter);
+            }
+            Some(recording) => {
+              println!(
+                "Got ENCOUNTER_START with name '{}', but \
+                 activity '{}' is still being recorded",
+                encounter.name, recording.activity
+              );
+            }
+          }
+        }
+        EncounterEnd(datetime, result) => {
           let Some(recording) = recorder.recording.as_mut() else {
-            recorder.start_recording(datetime, Activity::Raid(name));
             continue;
           };
 
-          if recording.is_mythicplus() {
-            recording.add_encounter(datetime, name);
-          } else {
-            println!(
-              "Got ENCOUNTER_START with name '{name}', but \
-               non-mythicplus activity '{}' is still being recorded",
-              recorder
-                .recording
-                .as_ref()
-                .expect("Checked for none")
-                .activity
-            );
-          }
-        }
-        EncounterEnd => {
-          if Some(true) == recorder.recording.as_ref().map(|r| r.is_raid()) {
-            recorder.stop_recording();
+          if recording.has_encounters() {
+            recorder.end_encounter(datetime, result);
+          } else if recording.is_raid() {
+            recording.end_raid(datetime, result);
+            post_roll.start(conf.post_roll_seconds.raid);
           }
         }
-        ChallengeModeStart(datetime, name) => {
-          if recorder.recording.is_none() {
-            recorder.start_recording(datetime, Activity::MythicPlus(name));
-            continue;
-          } else {
+        ChallengeModeStart(datetime, key) => {
+          if let Some(recording) = recorder.recording.as_ref() {
             println!(
-              "Got CHALLENGE_MODE_START with name '{name}', but \
+              "Got CHALLENGE_MODE_START with name '{}', but \
                activity {} is still being recorded!",
-              recorder
-                .recording
-                .as_ref()
-                .expect("Checked for none")
-                .activity
+              key.name, recording.activity
             );
+          } else {
+            recorder.start_recording(datetime, Activity::MythicPlus(key));
+            continue;
           }
         }
-        ChallengeModeEnd => {
-          if Some(true)
-            == recorder.recording.as_ref().map(|r| r.is_mythicplus())
+        ChallengeModeEnd(_, result) => {
+          if let Some(recording) = recorder
+            .recording
+            .as_mut()
+            .filter(|r| r.is_mythicplus())
           {
-            recorder.stop_recording();
+            recording.end_key(result);
+            post_roll.start(conf.post_roll_seconds.mythicplus);
           } else {
             println!(
               "Got CHALLENGE_MODE_END, but no mythicplus recording \
@@ -135,9 +258,77 @@ pub async fn main() -> Result<(), io::Error> {
             );
           }
         }
-        PlayerDeath(datetime, name) => {
-          if let Some(r) = recorder.recording.as_mut() {
-            r.add_death(datetime, name);
+        ArenaMatchStart(datetime, arena) => match recorder.recording.as_ref() {
+          Some(recording) if recording.is_solo_shuffle() => {
+            recorder.add_round(datetime, arena);
+          }
+          Some(recording) => {
+            println!(
+              "Got ARENA_MATCH_START for bracket '{}', but activity {} is \
+               still being recorded!",
+              arena.bracket, recording.activity
+            );
+          }
+          None if arena.is_solo_shuffle() => {
+            recorder
+              .start_recording(datetime, Activity::SoloShuffle(arena.clone()));
+            recorder.add_round(datetime, arena);
+          }
+          None => recorder.start_recording(datetime, Activity::Arena(arena)),
+        },
+        ArenaMatchEnd(_, result) => match recorder.recording.as_mut() {
+          Some(recording)
+            if matches!(
+              recording.activity,
+              Activity::Arena(_) | Activity::SoloShuffle(_)
+            ) =>
+          {
+            recording.end_arena(result);
+            post_roll.start(conf.post_roll_seconds.arena);
+          }
+          _ => {
+            println!("Got ARENA_MATCH_END, but no arena recording running");
+          }
+        },
+        ZoneChange(datetime, zone) => {
+          if recorder.recording.is_some() {
+            continue;
+          }
+
+          if let Some(activity) = Activity::entered(zone, &conf.instance_types)
+          {
+            recorder.start_recording(datetime, activity);
+          }
+        }
+        PlayerDeath(datetime, name) => recorder.add_death(datetime, name),
+        CombatantInfo(_, combatant) => recorder.add_participant(combatant),
+        ParseWarning(error) => {
+          eprintln!("Warning: Skipping unparseable line: {error}");
+        }
+        ReplayFinished => {
+          println!("Replay finished, hit Ctrl-C to exit");
+        }
+        RecordingSaved(file) => {
+          recorder.processed();
+          println!("Saved recording {}", file.to_string_lossy());
+          recorder.cleanup();
+          if exiting && !recorder.is_processing() {
+            break;
+          }
+        }
+        RecordingSkipped(reason) => {
+          eprintln!("Warning: Not recording {reason}");
+        }
+        RecordingWarning(problem) => eprintln!("Warning: {problem}"),
+        RecorderDied(reason) => {
+          eprintln!("Warning: Recorder stopped during recording: {reason}");
+          recorder.restart();
+        }
+        RecordingFailed(filename, error) => {
+          recorder.processed();
+          eprintln!("Error: Recording {filename} failed: {error}");
+          if exiting && !recorder.is_processing() {
+            break;
           }
         }
         IoErr(error) => {
@@ -145,12 +336,18 @@ pub async fn main() -> Result<(), io::Error> {
           break;
         }
         CtrlC => {
+          if recorder.recording.is_none() && recorder.is_processing() {
+            println!("Caught Ctrl-C, exiting once recordings are processed");
+            exiting = true;
+            continue;
+          }
           if recorder.recording.is_none() {
             println!("Caught Ctrl-C with no recording running. Exiting");
             break;
           }
 
           println!("Caught Ctrl-C, stopping current recording");
+          post_roll.cancel();
           recorder.stop_recording();
         }
       }
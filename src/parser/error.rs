@@ -0,0 +1,56 @@
+use std::{error::Error, fmt::Display};
+
+/// Error while parsing a single line of the combat log
+#[derive(Clone, Debug)]
+pub struct ParseError {
+  /// The offending line
+  pub line: String,
+  /// Index of the offending field (not counting the event type), if the error
+  /// is about a specific field
+  pub field: Option<usize>,
+  pub kind: ParseErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+  /// The line does not have the general shape of a combat log line
+  Malformed,
+  /// The timestamp could not be parsed
+  Timestamp,
+  /// A field the event type needs is not present
+  MissingField,
+  /// A field has an unexpected value
+  InvalidField,
+}
+
+impl ParseError {
+  pub fn new(line: &str, field: Option<usize>, kind: ParseErrorKind) -> Self {
+    Self {
+      line: line.to_string(),
+      field,
+      kind,
+    }
+  }
+}
+
+impl Display for ParseErrorKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Malformed => write!(f, "malformed line"),
+      Self::Timestamp => write!(f, "invalid timestamp"),
+      Self::MissingField => write!(f, "missing field"),
+      Self::InvalidField => write!(f, "invalid field"),
+    }
+  }
+}
+
+impl Display for ParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.field {
+      Some(idx) => write!(f, "{} {idx} in line '{}'", self.kind, self.line),
+      None => write!(f, "{} in line '{}'", self.kind, self.line),
+    }
+  }
+}
+
+impl Error for ParseError {}
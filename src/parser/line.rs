@@ -0,0 +1,244 @@
+use std::str::{self, FromStr};
+
+use chrono::NaiveDateTime;
+use memchr::memchr;
+
+use super::error::{ParseError, ParseErrorKind};
+
+/// Format of the timestamp at the start of every combat log line
+const TIME_FORMAT: &str = "%-m/%-d/%Y %H:%M:%S%.f";
+
+/// A single, tokenized line of the combat log. Borrows from the buffer it was
+/// parsed from, nothing is copied.
+///
+/// A line looks like
+/// `9/19/2024 20:14:04.1234  ENCOUNTER_START,2902,"Ulgrax the Devourer",16,20,2657`,
+/// i.e. a timestamp, two blanks, the event type and then comma-separated
+/// fields. Fields can be quoted strings (which may contain commas) or nested
+/// lists in brackets/parentheses (used by COMBATANT_INFO), commas inside those
+/// don't separate fields.
+#[derive(Debug, PartialEq)]
+pub struct LogLine<'a> {
+  /// The whole line, without line ending
+  pub raw: &'a str,
+  /// The raw timestamp, see [`LogLine::datetime`]
+  pub timestamp: &'a str,
+  /// The event type, e.g. `ENCOUNTER_START`
+  pub event: &'a str,
+  /// The fields following the event type, raw (i.e. quotes are kept)
+  pub fields: Vec<&'a str>,
+}
+
+impl<'a> LogLine<'a> {
+  /// Tokenizes `line`. A trailing `\r` (or `\n`) is ignored. Fails if the
+  /// line does not have the general shape of a combat log line.
+  pub fn parse(line: &'a [u8]) -> Result<Self, ParseError> {
+    let Ok(line) = str::from_utf8(line) else {
+      return Err(ParseError::new(
+        &String::from_utf8_lossy(line),
+        None,
+        ParseErrorKind::Malformed,
+      ));
+    };
+    let line = line.trim_end_matches(['\r', '\n']);
+    let malformed = || ParseError::new(line, None, ParseErrorKind::Malformed);
+
+    let firstblank = memchr(b' ', line.as_bytes()).ok_or_else(malformed)?;
+    let secondblank = memchr(b' ', &line.as_bytes()[firstblank + 1..])
+      .ok_or_else(malformed)?;
+    let (timestamp, rest) = line.split_at(firstblank + secondblank + 1);
+    let rest = rest.trim_start_matches(' ');
+
+    let (event, rest) = match memchr(b',', rest.as_bytes()) {
+      Some(idx) => (&rest[..idx], Some(&rest[idx + 1..])),
+      None => (rest, None),
+    };
+
+    if event.is_empty() {
+      return Err(malformed());
+    }
+
+    let fields = match rest {
+      Some(rest) => split_fields(rest).ok_or_else(malformed)?,
+      None => vec![],
+    };
+
+    Ok(Self {
+      raw: line,
+      timestamp,
+      event,
+      fields,
+    })
+  }
+
+  /// Returns the parsed timestamp of this line
+  pub fn datetime(&self) -> Result<NaiveDateTime, ParseError> {
+    NaiveDateTime::parse_from_str(self.timestamp, TIME_FORMAT)
+      .map_err(|_| self.error(None, ParseErrorKind::Timestamp))
+  }
+
+  /// Returns the raw field at `idx`
+  pub fn field(&self, idx: usize) -> Option<&'a str> {
+    self.fields.get(idx).copied()
+  }
+
+  /// Returns the field at `idx`, with surrounding quotes removed if present
+  pub fn unquoted(&self, idx: usize) -> Option<&'a str> {
+    self.field(idx).map(unquote)
+  }
+
+  /// Like [`LogLine::field`], but a missing field is an error
+  pub fn require(&self, idx: usize) -> Result<&'a str, ParseError> {
+    self
+      .field(idx)
+      .ok_or_else(|| self.error(Some(idx), ParseErrorKind::MissingField))
+  }
+
+  /// Like [`LogLine::unquoted`], but a missing field is an error
+  pub fn require_unquoted(&self, idx: usize) -> Result<&'a str, ParseError> {
+    self.require(idx).map(unquote)
+  }
+
+  /// Parses the field at `idx` into a `T`
+  pub fn parse_field<T: FromStr>(&self, idx: usize) -> Result<T, ParseError> {
+    self
+      .require(idx)?
+      .parse()
+      .map_err(|_| self.error(Some(idx), ParseErrorKind::InvalidField))
+  }
+
+  /// Creates an error about this line
+  pub fn error(
+    &self,
+    field: Option<usize>,
+    kind: ParseErrorKind,
+  ) -> ParseError {
+    ParseError::new(self.raw, field, kind)
+  }
+}
+
+/// Removes surrounding double quotes from `s`, if present
+pub fn unquote(s: &str) -> &str {
+  s.strip_prefix('"')
+    .and_then(|s| s.strip_suffix('"'))
+    .unwrap_or(s)
+}
+
+/// Splits `s` at commas that are neither inside a quoted string nor inside
+/// brackets/parentheses. Returns `None` on unbalanced quotes or brackets.
+fn split_fields(s: &str) -> Option<Vec<&str>> {
+  let mut fields = vec![];
+  let mut depth: usize = 0;
+  let mut quoted = false;
+  let mut start = 0;
+
+  for (idx, c) in s.bytes().enumerate() {
+    match c {
+      b'"' => quoted = !quoted,
+      _ if quoted => {}
+      b'[' | b'(' => depth += 1,
+      b']' | b')' => depth = depth.checked_sub(1)?,
+      b',' if depth == 0 => {
+        fields.push(&s[start..idx]);
+        start = idx + 1;
+      }
+      _ => {}
+    }
+  }
+
+  if quoted || depth != 0 {
+    return None;
+  }
+
+  fields.push(&s[start..]);
+  Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encounter_start() {
+    let l = b"9/19/2024 20:14:04.1234  ENCOUNTER_START,2902,\
+              \"Ulgrax the Devourer\",16,20,2657\r";
+    let line = LogLine::parse(l).unwrap();
+
+    assert_eq!(line.timestamp, "9/19/2024 20:14:04.1234");
+    assert_eq!(line.event, "ENCOUNTER_START");
+    assert_eq!(
+      line.fields,
+      ["2902", "\"Ulgrax the Devourer\"", "16", "20", "2657"]
+    );
+    assert_eq!(line.unquoted(1), Some("Ulgrax the Devourer"));
+    assert_eq!(line.field(5), None);
+    assert_eq!(line.parse_field::<u32>(0).unwrap(), 2902);
+    assert_eq!(
+      line.parse_field::<u32>(1).unwrap_err().kind,
+      ParseErrorKind::InvalidField
+    );
+    assert_eq!(
+      line.require(5).unwrap_err().kind,
+      ParseErrorKind::MissingField
+    );
+    assert_eq!(
+      line.datetime().unwrap().to_string(),
+      "2024-09-19 20:14:04.123400"
+    );
+  }
+
+  #[test]
+  fn commas_in_quotes() {
+    let l = b"1/2/2025 01:02:03.000  SPELL_CAST_SUCCESS,\"Foo, the Bar\",0x0";
+    let line = LogLine::parse(l).unwrap();
+
+    assert_eq!(line.fields, ["\"Foo, the Bar\"", "0x0"]);
+  }
+
+  #[test]
+  fn nested_brackets() {
+    let l = b"1/2/2025 01:02:03.000  COMBATANT_INFO,Player-1,1,(1,2,3),\
+              [(4,5,(6,7)),(8,9)],[],\"(,)\",0";
+    let line = LogLine::parse(l).unwrap();
+
+    assert_eq!(
+      line.fields,
+      [
+        "Player-1",
+        "1",
+        "(1,2,3)",
+        "[(4,5,(6,7)),(8,9)]",
+        "[]",
+        "\"(,)\"",
+        "0"
+      ]
+    );
+  }
+
+  #[test]
+  fn no_fields() {
+    let line = LogLine::parse(b"1/2/2025 01:02:03.000  FOO").unwrap();
+
+    assert_eq!(line.event, "FOO");
+    assert!(line.fields.is_empty());
+  }
+
+  #[test]
+  fn malformed() {
+    for l in [
+      &b""[..],
+      b"garbage",
+      b"1/2/2025 01:02:03.000  FOO,\"bar",
+      b"1/2/2025 01:02:03.000  FOO,[(bar]",
+      b"1/2/2025 01:02:03.000  FOO,bar)",
+      b"1/2/2025 \xff  FOO",
+    ] {
+      let e = LogLine::parse(l).unwrap_err();
+      assert_eq!(e.kind, ParseErrorKind::Malformed);
+      assert_eq!(e.field, None);
+    }
+
+    let line = LogLine::parse(b"1/2/2025 01:02  FOO").unwrap();
+    assert_eq!(line.datetime().unwrap_err().kind, ParseErrorKind::Timestamp);
+  }
+}
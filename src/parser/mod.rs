@@ -1,14 +1,20 @@
-use std::str;
+use std::time::Duration;
 
-use chrono::NaiveDateTime;
-use memchr::{memchr, memmem, memrchr};
+use memchr::memrchr;
 use tokio::sync::mpsc::Sender;
 
-use crate::events::Event;
+use crate::events::{
+  ArenaMatch, ArenaResult, ChallengeMode, ChallengeModeResult, Combatant,
+  Encounter, EncounterResult, Event, Zone,
+};
 
+mod error;
 mod flags;
+mod line;
 
+pub use error::{ParseError, ParseErrorKind};
 use flags::{Flags, HasFlag};
+pub use line::LogLine;
 
 #[derive(Default)]
 pub struct Parser {}
@@ -21,209 +27,518 @@ impl Parser {
   /// Parse the events in `buffer` into `Event`s and send them through the
   /// channel
   ///
-  /// * Advances start of buffer behind parsed stuff, does not modify buffer end
+  /// * Advances start of buffer behind the last complete line, does not modify
+  ///   buffer end
   /// * Should work correctly even if several ENCOUNTER_START/ENCOUNTER_END etc.
   ///   events are present (will swallow up the events that are superflous here)
-  ///
-  /// Very adhoc, might need to rethink this later
+  /// * Lines that can't be parsed are skipped and reported as
+  ///   `Event::ParseWarning`
   pub async fn parse(&self, buffer: &mut &[u8], tx: Sender<Event>) {
-    // handle ENCOUNTER_START
-    let startit = memmem::find_iter(buffer, "ENCOUNTER_START");
-    if let Some(startidx) = startit.last() {
-      // Have the buffer start at the start of this line
-      // If '\n' isn't found, this is the first line, so just keep the buffer
-      if let Some(nidx) = memrchr(b'\n', &buffer[..startidx]) {
-        *buffer = &buffer[nidx + 1..];
+    // Preserve the last line, if it's not complete
+    let Some(nidx) = memrchr(b'\n', buffer) else {
+      return;
+    };
+    let lines: Vec<Result<LogLine, ParseError>> = buffer[..nidx]
+      .split(|c| *c == b'\n')
+      .filter(|l| !l.is_empty() && *l != b"\r")
+      .map(LogLine::parse)
+      .collect();
+    *buffer = &buffer[nidx + 1..];
+
+    let mut idx = 0;
+    while idx < lines.len() {
+      let line = match &lines[idx] {
+        Ok(line) => line,
+        Err(e) => {
+          tx.send(Event::ParseWarning(e.clone()))
+            .await
+            .expect("Event channel");
+          idx += 1;
+          continue;
+        }
+      };
+
+      // Activity is already over, nothing to record
+      if let Some(endidx) = end_event(line.event)
+        .and_then(|end| find_event(&lines[idx..], end))
+      {
+        idx += endidx + 1;
+        continue;
       }
 
-      if self.skip_behind(buffer, "ENCOUNTER_END") {
-        return;
+      let event = match line.event {
+        "ENCOUNTER_START" => encounter_from_line(line)
+          .and_then(|e| Ok(Event::EncounterStart(line.datetime()?, e)))
+          .map(Some),
+        "ENCOUNTER_END" => encounter_result_from_line(line)
+          .and_then(|r| Ok(Event::EncounterEnd(line.datetime()?, r)))
+          .map(Some),
+        "CHALLENGE_MODE_START" => challenge_mode_from_line(line)
+          .and_then(|c| Ok(Event::ChallengeModeStart(line.datetime()?, c)))
+          .map(Some),
+        "CHALLENGE_MODE_END" => challenge_mode_result_from_line(line)
+          .and_then(|r| Ok(Event::ChallengeModeEnd(line.datetime()?, r)))
+          .map(Some),
+        "ARENA_MATCH_START" => arena_match_from_line(line)
+          .and_then(|a| Ok(Event::ArenaMatchStart(line.datetime()?, a)))
+          .map(Some),
+        "ARENA_MATCH_END" => arena_result_from_line(line)
+          .and_then(|r| Ok(Event::ArenaMatchEnd(line.datetime()?, r)))
+          .map(Some),
+        "ZONE_CHANGE" => zone_from_line(line)
+          .and_then(|z| Ok(Event::ZoneChange(line.datetime()?, z)))
+          .map(Some),
+        "COMBATANT_INFO" => combatant_from_line(line)
+          .and_then(|c| Ok(Event::CombatantInfo(line.datetime()?, c)))
+          .map(Some),
+        "UNIT_DIED" => player_death_from_line(line).and_then(|name| {
+          name
+            .map(|n| Ok(Event::PlayerDeath(line.datetime()?, n)))
+            .transpose()
+        }),
+        _ => Ok(None),
+      };
+
+      match event {
+        Ok(Some(e)) => tx.send(e).await.expect("Event channel"),
+        Ok(None) => {}
+        Err(e) => tx
+          .send(Event::ParseWarning(e))
+          .await
+          .expect("Event channel"),
       }
 
-      // This is the important part: ENCOUNTER_START, but not ENCOUNTER_END
-      let dt = datetime_from_line(buffer);
-      let encounter = encounter_from_line(buffer);
+      idx += 1;
+    }
+  }
+}
 
-      tx.send(Event::EncounterStart(dt, encounter))
-        .await
-        .expect("Event channel");
+/// Returns the event type ending the activity started by `event`, if `event`
+/// starts an activity
+fn end_event(event: &str) -> Option<&'static str> {
+  match event {
+    "ENCOUNTER_START" => Some("ENCOUNTER_END"),
+    "CHALLENGE_MODE_START" => Some("CHALLENGE_MODE_END"),
+    "ARENA_MATCH_START" => Some("ARENA_MATCH_END"),
+    _ => None,
+  }
+}
 
-      self.skip_to_next_line(buffer);
-    } else {
-      //handle CHALLENGE_MODE_START
-      let startit = memmem::find_iter(buffer, "CHALLENGE_MODE_START");
+/// Returns the index of the first line in `lines` with event type `event`
+fn find_event(
+  lines: &[Result<LogLine, ParseError>],
+  event: &str,
+) -> Option<usize> {
+  lines
+    .iter()
+    .position(|l| l.as_ref().is_ok_and(|l| l.event == event))
+}
 
-      if let Some(startidx) = startit.last() {
-        // Have the buffer start at the start of this line
-        // If '\n' isn't found, this is the first line, so just keep the buffer
-        if let Some(nidx) = memrchr(b'\n', &buffer[..startidx]) {
-          *buffer = &buffer[nidx + 1..];
-        }
+/// Returns the encounter data
+///
+/// Only works correctly on lines containing ENCOUNTER_START
+fn encounter_from_line(line: &LogLine) -> Result<Encounter, ParseError> {
+  // ENCOUNTER_START,encounterID,encounterName,difficultyID,groupSize,
+  //                 instanceID
+  Ok(Encounter {
+    id: line.parse_field(0)?,
+    name: line.require_unquoted(1)?.to_string(),
+    difficulty: line.parse_field::<u32>(2)?.into(),
+    group_size: line.parse_field(3)?,
+    instance_id: line.parse_field(4)?,
+  })
+}
 
-        if self.skip_behind(buffer, "CHALLENGE_MODE_END") {
-          return;
-        }
+/// Returns the outcome of an encounter
+///
+/// Only works correctly on lines containing ENCOUNTER_END
+fn encounter_result_from_line(
+  line: &LogLine,
+) -> Result<EncounterResult, ParseError> {
+  // ENCOUNTER_END,encounterID,encounterName,difficultyID,groupSize,success,
+  //               fightTime
+  Ok(EncounterResult {
+    id: line.parse_field(0)?,
+    success: parse_bool(line, 4)?,
+    duration: Duration::from_millis(line.parse_field(5)?),
+  })
+}
 
-        // This is the important part: ENCOUNTER_START, but not ENCOUNTER_END
-        let dt = datetime_from_line(buffer);
-        let dungeon = dungeon_from_line(buffer);
+/// Returns the key data
+///
+/// Only works correctly on lines containing CHALLENGE_MODE_START
+fn challenge_mode_from_line(
+  line: &LogLine,
+) -> Result<ChallengeMode, ParseError> {
+  // CHALLENGE_MODE_START,zoneName,instanceID,challengeModeID,keystoneLevel,
+  //                      [affixID,...]
+  let affixes = line
+    .require(4)?
+    .strip_prefix('[')
+    .and_then(|a| a.strip_suffix(']'))
+    .and_then(|a| {
+      a.split(',')
+        .filter(|a| !a.is_empty())
+        .map(|a| a.parse().ok())
+        .collect()
+    })
+    .ok_or_else(|| line.error(Some(4), ParseErrorKind::InvalidField))?;
+
+  Ok(ChallengeMode {
+    name: line.require_unquoted(0)?.to_string(),
+    map_id: line.parse_field(1)?,
+    challenge_mode_id: line.parse_field(2)?,
+    keystone_level: line.parse_field(3)?,
+    affixes,
+  })
+}
 
-        tx.send(Event::ChallengeModeStart(dt, dungeon))
-          .await
-          .expect("Event channel");
+/// Returns the outcome of a key
+///
+/// Only works correctly on lines containing CHALLENGE_MODE_END
+fn challenge_mode_result_from_line(
+  line: &LogLine,
+) -> Result<ChallengeModeResult, ParseError> {
+  // CHALLENGE_MODE_END,instanceID,success,keystoneLevel,totalTime,
+  //                    [rating,ratingChange]
+  let optional = |idx| line.field(idx).map(|_| line.parse_field(idx));
+
+  Ok(ChallengeModeResult {
+    map_id: line.parse_field(0)?,
+    success: parse_bool(line, 1)?,
+    keystone_level: line.parse_field(2)?,
+    duration: Duration::from_millis(line.parse_field(3)?),
+    rating: optional(4).transpose()?,
+    rating_change: optional(5).transpose()?,
+  })
+}
 
-        self.skip_to_next_line(buffer);
-      }
-    }
+/// Returns the arena match data
+///
+/// Only works correctly on lines containing ARENA_MATCH_START
+fn arena_match_from_line(line: &LogLine) -> Result<ArenaMatch, ParseError> {
+  // ARENA_MATCH_START,instanceID,isRanked,matchType,teamID
+  Ok(ArenaMatch {
+    instance_id: line.parse_field(0)?,
+    rated: line.require(1)? != "0",
+    bracket: line.require_unquoted(2)?.to_string(),
+    team_id: line.parse_field(3)?,
+  })
+}
 
-    if let Some(endidx) = memmem::find_iter(buffer, "ENCOUNTER_END").next() {
-      self.handle_deaths(&buffer[..endidx], &tx).await;
-      tx.send(Event::EncounterEnd).await.expect("Event channel");
-      *buffer = &buffer[endidx..];
-      self.skip_to_next_line(buffer);
-    } else if let Some(endidx) = memmem::find_iter(buffer, "CHALLENGE_MODE_END").next() {
-      self.handle_deaths(&buffer[..endidx], &tx).await;
-      tx.send(Event::ChallengeModeEnd).await.expect("Event channel");
-      *buffer = &buffer[endidx..];
-      self.skip_to_next_line(buffer);
-    } else {
-      // No *_END -> just handle deaths
-      self.handle_deaths(buffer, &tx).await;
-    }
+/// Returns the outcome of an arena match
+///
+/// Only works correctly on lines containing ARENA_MATCH_END
+fn arena_result_from_line(line: &LogLine) -> Result<ArenaResult, ParseError> {
+  // ARENA_MATCH_END,winningTeam,matchDuration,newRatingTeam0,newRatingTeam1
+  Ok(ArenaResult {
+    winning_team: line.parse_field(0)?,
+    duration: Duration::from_secs(line.parse_field(1)?),
+    ratings: [line.parse_field(2)?, line.parse_field(3)?],
+  })
+}
 
-    //preserve the last line, if it's not complete
-    if let Some(nidx) = memrchr(b'\n', buffer) {
-      if nidx < buffer.len() {
-        *buffer = &buffer[nidx + 1..];
-      } else {
-        *buffer = &[]
-      }
-    }
+/// Returns the zone that was entered
+///
+/// Only works correctly on lines containing ZONE_CHANGE
+fn zone_from_line(line: &LogLine) -> Result<Zone, ParseError> {
+  // ZONE_CHANGE,instanceID,zoneName,difficultyID
+  Ok(Zone {
+    instance_id: line.parse_field(0)?,
+    name: line.require_unquoted(1)?.to_string(),
+    difficulty_id: line.parse_field(2)?,
+  })
+}
+
+/// Returns a participant of an encounter, key or arena match
+///
+/// Only works correctly on lines containing COMBATANT_INFO
+fn combatant_from_line(line: &LogLine) -> Result<Combatant, ParseError> {
+  // COMBATANT_INFO,playerGUID,faction,strength,agility,stamina,intelligence,
+  //                dodge,parry,block,critMelee,critRanged,critSpell,speed,
+  //                lifesteal,hasteMelee,hasteRanged,hasteSpell,avoidance,
+  //                mastery,versatilityDamageDone,versatilityHealingDone,
+  //                versatilityDamageTaken,armor,currentSpecID,[talents],...
+  Ok(Combatant {
+    guid: line.require(0)?.to_string(),
+    faction: line.parse_field(1)?,
+    spec_id: line.parse_field(23)?,
+  })
+}
+
+/// Parses a `0`/`1` field
+fn parse_bool(line: &LogLine, idx: usize) -> Result<bool, ParseError> {
+  match line.require(idx)? {
+    "0" => Ok(false),
+    "1" => Ok(true),
+    _ => Err(line.error(Some(idx), ParseErrorKind::InvalidField)),
   }
+}
 
-  /// Parses the UNIT_DIED events in `buffer`, send the events through the
-  /// channel. Does not adjust buffer's start. Filters out uninteresting events.
-  async fn handle_deaths(&self, buffer: &[u8], tx: &Sender<Event>) {
-    let it = memmem::find_iter(buffer, "UNIT_DIED");
-
-    for idx in it {
-      let nidx = memrchr(b'\n', &buffer[..idx]);
-      let linestart = nidx.map(|i| i + 1).unwrap_or(0);
-      let endidx = memchr(b'\n', &buffer[idx..]);
-      let lineend = endidx.map(|i| i + idx).unwrap_or(buffer.len());
-      let mut line = &buffer[linestart..lineend];
-      let datetime = datetime_from_line(&mut line);
-
-      if let Some(idx) = memmem::find_iter(line, ",Player-").next() {
-        let line = &line[idx..];
-        let nstart = memchr::memchr(b'"', line).expect("Player name");
-        let nend =
-          memchr::memchr(b'"', &line[nstart + 1..]).expect("Player name");
-
-        let flagstart = nstart + nend + 3;
-        let flagend =
-          memchr::memchr(b',', &line[flagstart..]).expect("Player flags");
-        let flag = str::from_utf8(&line[flagstart..flagstart + flagend])
-          .expect("Player flag");
-        let flag = flag.strip_prefix("0x").expect("Hexadecimal");
-        let flag = i32::from_str_radix(flag, 16).expect("Player flags");
-
-        // UnitUnconsciousAtDeath
-        // -2 because of windows' line endings, -1 in case we're at the end of
-        // the buffer
-        // No false positives because of short-circuiting
-        let line_ends_with_0 =
-          buffer[lineend - 1] == b'0' || buffer[lineend - 2] == b'0';
-
-        if flag.has_flag(Flags::ControlPlayer)
-          && flag.has_flag(Flags::TypePlayer)
-          && line_ends_with_0
-        {
-          let name =
-            String::from_utf8_lossy(&line[nstart + 1..nstart + nend + 1]);
-          tx.send(Event::PlayerDeath(datetime, name.to_string()))
-            .await
-            .expect("Event channel");
-        }
-      }
-    }
+/// Returns the name of the died unit, if it is a player that actually died
+/// (instead of e.g. Feign Death or a pet)
+///
+/// Only works correctly on lines containing UNIT_DIED
+fn player_death_from_line(
+  line: &LogLine,
+) -> Result<Option<String>, ParseError> {
+  // UNIT_DIED,sourceGUID,sourceName,sourceFlags,sourceRaidFlags,
+  //           destGUID,destName,destFlags,destRaidFlags,unconsciousOnDeath
+  if !line.require(4)?.starts_with("Player-") {
+    return Ok(None);
   }
 
-  /// Advances buffer start behind the last line containing marker
-  /// Returns true if it DID find such a line, false otherwise
-  fn skip_behind(&self, buffer: &mut &[u8], marker: &'static str) -> bool {
-    let mut endit = memmem::rfind_iter(buffer, marker);
-
-    if let Some(endidx) = endit.next() {
-      *buffer = &buffer[endidx..];
-      self.skip_to_next_line(buffer);
-      true
-    } else {
-      false
-    }
+  let flag = line
+    .require(6)?
+    .strip_prefix("0x")
+    .and_then(|f| i32::from_str_radix(f, 16).ok())
+    .ok_or_else(|| line.error(Some(6), ParseErrorKind::InvalidField))?;
+
+  // UnitUnconsciousAtDeath
+  let unconscious = line.require(8)? != "0";
+
+  if flag.has_flag(Flags::ControlPlayer)
+    && flag.has_flag(Flags::TypePlayer)
+    && !unconscious
+  {
+    Ok(Some(line.require_unquoted(5)?.to_string()))
+  } else {
+    Ok(None)
   }
+}
 
-  /// Advance the start of the buffer behind the next b'\n'
-  fn skip_to_next_line(&self, buffer: &mut &[u8]) {
-    if buffer.is_empty() {
-      return;
+#[cfg(test)]
+mod tests {
+  use tokio::sync::mpsc;
+
+  use super::*;
+  use crate::events::{Difficulty, InstanceType};
+
+  async fn parse_all(mut buffer: &[u8]) -> (Vec<Event>, &[u8]) {
+    let (tx, mut rx) = mpsc::channel(16);
+    Parser::new().parse(&mut buffer, tx).await;
+
+    let mut events = vec![];
+    while let Some(e) = rx.recv().await {
+      events.push(e);
     }
+    (events, buffer)
+  }
 
-    if let Some(nidx) = memchr(b'\n', buffer) {
-      // \n might be the last char in the buffer
-      if nidx == buffer.len() - 1 {
-        *buffer = &[]
-      } else {
-        *buffer = &buffer[nidx + 1..];
+  #[tokio::test]
+  async fn encounter() {
+    let log = b"9/19/2024 20:14:04.1234  ENCOUNTER_START,2902,\
+                \"Ulgrax the Devourer\",16,20,2657\r\n\
+                9/19/2024 20:14:05.1234  SPELL_CAST_SUCCESS,\
+                \"ENCOUNTER_END\"\r\n\
+                9/19/2024 20:14:06.1234  UNIT_DIED,0000000000000000,nil,\
+                0x80000000,0x80000000,Player-1-2,\"Foo-Bar\",0x514,0x0,0\r\n\
+                9/19/2024 20:14:07.1234  UNIT_DIED,0000000000000000,nil,\
+                0x80000000,0x80000000,Player-1-3,\"Hunter-Bar\",0x514,0x0,1\r\n\
+                9/19/2024 20:14:08";
+    let (events, rest) = parse_all(log).await;
+
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+      &events[0],
+      Event::EncounterStart(_, e) if *e == Encounter {
+        id: 2902,
+        name: "Ulgrax the Devourer".to_string(),
+        difficulty: Difficulty::Mythic,
+        group_size: 20,
+        instance_id: 2657,
       }
-    } else {
-      *buffer = &[];
-    }
+    ));
+    assert!(matches!(&events[1], Event::PlayerDeath(_, n) if n == "Foo-Bar"));
+    assert_eq!(rest, b"9/19/2024 20:14:08");
   }
-}
 
-/// Returns the encounter name, blanks are replaced by underscores. Does not
-/// adjust line's start
-///
-/// Probably only works correctly on lines containing ENCOUNTER_START
-fn encounter_from_line(line: &mut &[u8]) -> String {
-  let firstmark = memchr(b'"', line).expect("ENCOUNTER_START name format");
-  let secondmark =
-    memchr(b'"', &line[firstmark + 1..]).expect("ENCOUNTER_START name format");
-  let v: Vec<u8> = line[firstmark + 1..firstmark + secondmark + 1]
-    .iter()
-    .map(|c| if c == &b' ' { b'_' } else { *c })
-    .collect();
+  #[tokio::test]
+  async fn skip_finished() {
+    let log = b"9/19/2024 20:14:04.1234  ENCOUNTER_END,2901,\"A\",16,20,1,\
+                312345\n\
+                9/19/2024 20:14:05.1234  ENCOUNTER_START,2902,\"B\",16,20,2657\n\
+                9/19/2024 20:14:06.1234  ENCOUNTER_END,2902,\"B\",16,20,0,1000\n\
+                9/19/2024 20:14:07.1234  CHALLENGE_MODE_START,\
+                \"Ara-Kara, City of Echoes\",2660,503,10,[10,9,152]\n";
+    let (events, rest) = parse_all(log).await;
+
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+      &events[0],
+      Event::EncounterEnd(_, r) if *r == EncounterResult {
+        id: 2901,
+        success: true,
+        duration: Duration::from_millis(312345),
+      }
+    ));
+    assert!(matches!(
+      &events[1],
+      Event::ChallengeModeStart(_, c) if *c == ChallengeMode {
+        name: "Ara-Kara, City of Echoes".to_string(),
+        map_id: 2660,
+        challenge_mode_id: 503,
+        keystone_level: 10,
+        affixes: vec![10, 9, 152],
+      }
+    ));
+    assert!(rest.is_empty());
+  }
 
-  String::from_utf8(v).expect("Valid UTF8")
-}
-///
-/// Returns the dungeon name, blanks are replaced by underscores. Does not
-/// adjust line's start
-///
-/// Probably only works correctly on lines containing CHALLENGE_MODE_START
-fn dungeon_from_line(line: &mut &[u8]) -> String {
-  let firstmark = memchr(b'"', line).expect("CHALLENGE_MODE_START name format");
-  let secondmark = memchr(b'"', &line[firstmark + 1..])
-    .expect("CHALLENGE_MODE_START name format");
-  let v: Vec<u8> = line[firstmark + 1..firstmark + secondmark + 1]
-    .iter()
-    .map(|c| if c == &b' ' { b'_' } else { *c })
-    .collect();
+  #[tokio::test]
+  async fn combatant_info() {
+    let log = b"9/19/2024 20:14:04.2234  COMBATANT_INFO,Player-1-2,1,100,\
+                200,300,400,0,0,0,10,10,10,0,0,20,20,20,0,30,5,5,5,1000,\
+                577,[(1,2,1),(3,4,1)],(0,0,0,0),[(1,2,(),(),())],[],0,0,0\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 1);
+    assert!(matches!(
+      &events[0],
+      Event::CombatantInfo(_, c) if *c == Combatant {
+        guid: "Player-1-2".to_string(),
+        faction: 1,
+        spec_id: 577,
+      }
+    ));
+  }
 
-  String::from_utf8(v).expect("Valid UTF8")
-}
+  #[tokio::test]
+  async fn challenge_mode_end() {
+    let log = b"9/19/2024 20:44:07.1234  CHALLENGE_MODE_END,2660,0,10,\
+                2100000,2845.384521,-3.5\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 1);
+    assert!(matches!(
+      &events[0],
+      Event::ChallengeModeEnd(_, r) if *r == ChallengeModeResult {
+        map_id: 2660,
+        success: false,
+        keystone_level: 10,
+        duration: Duration::from_millis(2100000),
+        rating: Some(2845.384521),
+        rating_change: Some(-3.5),
+      }
+    ));
+  }
 
-/// Returns the Datetime of a log entry. Does not adjust line's start.
-fn datetime_from_line(line: &mut &[u8]) -> NaiveDateTime {
-  let firstblank = memchr(b' ', line).expect("Log Time Format");
-  let secondblank =
-    memchr(b' ', &line[firstblank + 1..]).expect("Log Time Format");
-  let dtstr =
-    str::from_utf8(&line[..firstblank + secondblank + 1]).expect("Valid UTF-8");
-  let datetime = NaiveDateTime::parse_from_str(dtstr, "%-m/%-d/%Y %H:%M:%S%.f")
-    .expect("Time format");
-  *line = &line[firstblank + secondblank + 1..];
-
-  datetime
+  #[tokio::test]
+  async fn arena() {
+    let log = b"9/19/2024 21:00:00.0000  ARENA_MATCH_START,1505,1,3v3,1\n\
+                9/19/2024 21:00:30.0000  UNIT_DIED,0000000000000000,nil,\
+                0x80000000,0x80000000,Player-1-2,\"Foo-Bar\",0x548,0x0,0\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+      &events[0],
+      Event::ArenaMatchStart(_, a) if *a == ArenaMatch {
+        instance_id: 1505,
+        rated: true,
+        bracket: "3v3".to_string(),
+        team_id: 1,
+      }
+    ));
+    assert!(matches!(&events[1], Event::PlayerDeath(_, n) if n == "Foo-Bar"));
+
+    let log = b"9/19/2024 21:03:08.0000  ARENA_MATCH_END,0,188,1756,1787\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 1);
+    assert!(matches!(
+      &events[0],
+      Event::ArenaMatchEnd(_, r) if *r == ArenaResult {
+        winning_team: 0,
+        duration: Duration::from_secs(188),
+        ratings: [1756, 1787],
+      }
+    ));
+  }
+
+  #[tokio::test]
+  async fn zone_change() {
+    let log =
+      b"9/19/2024 21:00:00.0000  ZONE_CHANGE,2107,\"Arathi Basin\",0\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 1);
+    let Event::ZoneChange(_, zone) = &events[0] else {
+      panic!("Expected ZoneChange, got {:?}", events[0]);
+    };
+    assert_eq!(
+      *zone,
+      Zone {
+        instance_id: 2107,
+        name: "Arathi Basin".to_string(),
+        difficulty_id: 0,
+      }
+    );
+    assert!(zone.is_battleground());
+    assert_eq!(zone.instance_type(), None);
+
+    let log = b"9/19/2024 21:00:00.0000  ZONE_CHANGE,2690,\"Fungal Folly\",208\n\
+                9/19/2024 21:00:00.0000  ZONE_CHANGE,2652,\"The Stonevault\",2\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+      &events[0],
+      Event::ZoneChange(_, z) if z.instance_type() == Some(InstanceType::Delve)
+    ));
+    assert!(matches!(
+      &events[1],
+      Event::ZoneChange(_, z)
+        if z.instance_type() == Some(InstanceType::Dungeon)
+          && z.difficulty() == Difficulty::Heroic
+    ));
+  }
+
+  #[tokio::test]
+  async fn warnings() {
+    let log = b"9/19/2024 20:14:04.1234  ENCOUNTER_START,2902,\"A\",x,20,1\n\
+                garbage\n\
+                \n\
+                9/19/2024 20:14:06.1234  UNIT_DIED,0000000000000000,nil,\
+                0x80000000,0x80000000,Player-1-2,\"Foo-Bar\",514,0x0,0\n\
+                9/19/2024 20:14:07.1234  CHALLENGE_MODE_END,2660,1,10,1800000\n";
+    let (events, _) = parse_all(log).await;
+
+    assert_eq!(events.len(), 4);
+    assert!(matches!(
+      &events[0],
+      Event::ParseWarning(ParseError {
+        field: Some(2),
+        kind: ParseErrorKind::InvalidField,
+        ..
+      })
+    ));
+    assert!(matches!(
+      &events[1],
+      Event::ParseWarning(ParseError {
+        field: None,
+        kind: ParseErrorKind::Malformed,
+        ..
+      })
+    ));
+    assert!(matches!(
+      &events[2],
+      Event::ParseWarning(ParseError {
+        field: Some(6),
+        kind: ParseErrorKind::InvalidField,
+        ..
+      })
+    ));
+    assert!(matches!(
+      &events[3],
+      Event::ChallengeModeEnd(_, r) if *r == ChallengeModeResult {
+        map_id: 2660,
+        success: true,
+        keystone_level: 10,
+        duration: Duration::from_millis(1800000),
+        rating: None,
+        rating_change: None,
+      }
+    ));
+  }
 }
@@ -0,0 +1,415 @@
+use std::{
+  io,
+  path::{Path, PathBuf},
+  process::Stdio,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+use nix::sys::signal::Signal;
+use tokio::{
+  io::AsyncWriteExt,
+  process::{Child, Command},
+  task::JoinHandle,
+  time::{sleep, timeout},
+};
+
+use super::{
+  obs::ObsBackend,
+  process::{log_stderr, rotate_logs, signal, wait_or_kill},
+  replaybuffer::ReplayBufferBackend,
+};
+use crate::config::{executable, Backend, ProgrsConfig, RecorderConfig};
+
+/// A tool doing the actual recording
+pub trait RecorderBackend: Send {
+  /// Starts recording into `outfile`
+  fn start(&mut self, outfile: &str) -> io::Result<()>;
+
+  /// Stops the running recording. The returned future resolves once the
+  /// output file is complete, it fails if the recorder did not exit cleanly.
+  fn stop(&mut self) -> BoxFuture<'static, io::Result<()>>;
+
+  /// Marks the current position in the recording, if the backend supports
+  /// that. Chapters are merged into the file after recording anyways.
+  fn add_marker(&mut self, _name: &str) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// Whether the recorder is still running
+  fn health(&mut self) -> Health;
+
+  /// The wall clock time of the first frame of the current recording, if
+  /// known. Recorders need a moment to start capturing, with a pre-roll it is
+  /// before the call to `start`.
+  fn video_start(&self) -> Option<Instant> {
+    None
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Health {
+  Running,
+  /// Not running, with the reason
+  Stopped(String),
+}
+
+/// Creates the backend configured in `conf`. Recorder processes log their
+/// stderr into `logdir`.
+pub fn create(
+  conf: &ProgrsConfig,
+  logdir: Option<PathBuf>,
+) -> Box<dyn RecorderBackend> {
+  let rec = &conf.recorder;
+
+  let backend = match rec.backend {
+    Backend::GpuScreenRecorder if rec.replay_buffer_seconds.is_some() => {
+      let ffmpeg = Some(rec.ffmpeg.clone()).filter(|f| executable(f).is_ok());
+      let mut backend = ReplayBufferBackend::new(
+        rec.command.clone(),
+        rec.args.clone(),
+        rec.replay_buffer_seconds.expect("Checked above"),
+        rec.pre_roll_seconds,
+        conf.viddir.clone(),
+        ffmpeg,
+      );
+      if let Some(filter) = &rec.stderr_filter {
+        backend.stderr_filter.clone_from(filter);
+      }
+      backend.logdir = logdir;
+      backend.start_buffering();
+      return Box::new(backend);
+    }
+    Backend::GpuScreenRecorder => ProcessBackend::gpu_screen_recorder,
+    Backend::Ffmpeg => ProcessBackend::ffmpeg,
+    Backend::WfRecorder => ProcessBackend::wf_recorder,
+    Backend::Obs => return Box::new(ObsBackend::new(&conf.obs)),
+  };
+  let mut backend = backend(rec.command.clone(), rec.args.clone());
+  backend.configure(rec);
+  backend.logdir = logdir;
+
+  Box::new(backend)
+}
+
+/// The fps updates gpu-screen-recorder keeps printing
+pub const GSR_STDERR_FILTER: [&str; 2] = ["update fps", "damage fps"];
+
+/// How long to wait for a recorder to write its output file
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a recorder gets to finish its output file when stopped
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for the rest of the recorder's stderr after it exited
+const LOGGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a recorder process is told to finish its output file
+#[derive(Clone, Copy)]
+enum StopWith {
+  Signal(Signal),
+  /// Write this to the recorder's stdin
+  Stdin(&'static [u8]),
+}
+
+/// A recorder running as child process
+pub struct ProcessBackend {
+  command: String,
+  args: Vec<String>,
+  /// Switch designating the output file, `None` for positional output
+  outputswitch: Option<String>,
+  stop_with: StopWith,
+  /// Stderr lines containing any of these are not logged
+  stderr_filter: Vec<String>,
+  /// Where the stderr of each recording is logged, printed if `None`
+  logdir: Option<PathBuf>,
+  process: Option<Child>,
+  /// Writes the recorder's stderr into `logfile`
+  logger: Option<JoinHandle<()>>,
+  logfile: Option<PathBuf>,
+  /// When the recorder started writing the output file
+  capture_start: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ProcessBackend {
+  /// gpu-screen-recorder, output with `-o`, stopped with SIGINT
+  pub fn gpu_screen_recorder(command: String, args: Vec<String>) -> Self {
+    Self {
+      command,
+      args,
+      outputswitch: Some("-o".to_string()),
+      stop_with: StopWith::Signal(Signal::SIGINT),
+      stderr_filter: GSR_STDERR_FILTER.map(String::from).to_vec(),
+      logdir: None,
+      process: None,
+      logger: None,
+      logfile: None,
+      capture_start: Arc::default(),
+    }
+  }
+
+  /// ffmpeg, output is the last argument, stopped by sending `q`
+  pub fn ffmpeg(command: String, args: Vec<String>) -> Self {
+    Self {
+      command,
+      args,
+      outputswitch: None,
+      stop_with: StopWith::Stdin(b"q"),
+      stderr_filter: vec![],
+      logdir: None,
+      process: None,
+      logger: None,
+      logfile: None,
+      capture_start: Arc::default(),
+    }
+  }
+
+  /// wf-recorder, output with `-f`, stopped with SIGINT
+  pub fn wf_recorder(command: String, args: Vec<String>) -> Self {
+    Self {
+      command,
+      args,
+      outputswitch: Some("-f".to_string()),
+      stop_with: StopWith::Signal(Signal::SIGINT),
+      stderr_filter: vec![],
+      logdir: None,
+      process: None,
+      logger: None,
+      logfile: None,
+      capture_start: Arc::default(),
+    }
+  }
+
+  /// Overrides the conventions of the backend with the ones set in `rec`
+  fn configure(&mut self, rec: &RecorderConfig) {
+    if let Some(switch) = &rec.outputswitch {
+      self.outputswitch = Some(switch.clone()).filter(|s| !s.is_empty());
+    }
+    if let Some(filter) = &rec.stderr_filter {
+      self.stderr_filter.clone_from(filter);
+    }
+  }
+
+  /// The command recording into `outfile`
+  fn command(&self, outfile: &str) -> Command {
+    let mut command = Command::new(&self.command);
+    command.args(&self.args);
+    if let Some(switch) = &self.outputswitch {
+      command.arg(switch);
+    }
+    command.arg(outfile);
+    command
+  }
+}
+
+impl RecorderBackend for ProcessBackend {
+  fn start(&mut self, outfile: &str) -> io::Result<()> {
+    if self.process.is_some() {
+      return Err(io::Error::other("Recorder is already running"));
+    }
+    *self.capture_start.lock().expect("Capture start lock") = None;
+
+    let mut process = self
+      .command(outfile)
+      .stderr(Stdio::piped())
+      .stdin(Stdio::piped())
+      .spawn()?;
+
+    // Read stderr all the time, a full pipe would block the recorder
+    self.logfile = self.logdir.as_ref().map(|dir| {
+      if let Err(e) = rotate_logs(dir) {
+        println!("Could not remove old recorder logs: {e}");
+      }
+      let name = Path::new(outfile).file_stem().unwrap_or_default();
+      dir.join(name).with_extension("log")
+    });
+    self.logger = Some(tokio::spawn(log_stderr(
+      process.stderr.take().expect("Stderr is piped"),
+      self.logfile.clone(),
+      self.stderr_filter.clone(),
+    )));
+
+    self.process = Some(process);
+    tokio::spawn(detect_capture(
+      outfile.to_string(),
+      self.capture_start.clone(),
+    ));
+    Ok(())
+  }
+
+  fn video_start(&self) -> Option<Instant> {
+    *self.capture_start.lock().expect("Capture start lock")
+  }
+
+  fn stop(&mut self) -> BoxFuture<'static, io::Result<()>> {
+    let process = self.process.take();
+    let stop_with = self.stop_with;
+    let logger = self.logger.take();
+    let logfile = self.logfile.take();
+
+    Box::pin(async move {
+      let Some(mut process) = process else {
+        return Err(io::Error::other("Recorder is not running"));
+      };
+
+      // It might have died already
+      if let (Some(id), None) = (process.id(), process.try_wait()?) {
+        println!("Stopping recorder {id}");
+        match stop_with {
+          StopWith::Signal(s) => signal(&process, s)?,
+          StopWith::Stdin(input) => {
+            let stdin = process.stdin.as_mut().expect("Stdin is piped");
+            stdin.write_all(input).await?;
+            stdin.flush().await?;
+          }
+        }
+      }
+
+      let exitstatus = wait_or_kill(&mut process, STOP_TIMEOUT).await?;
+      // Children of the recorder might keep stderr open
+      if let Some(logger) = logger {
+        let _ = timeout(LOGGER_TIMEOUT, logger).await;
+      }
+
+      if !exitstatus.success() {
+        let log = match logfile {
+          Some(logfile) => format!(", see {}", logfile.display()),
+          None => String::new(),
+        };
+        return Err(io::Error::other(format!(
+          "Recorder exited with status {exitstatus}{log}"
+        )));
+      }
+
+      Ok(())
+    })
+  }
+
+  fn health(&mut self) -> Health {
+    let Some(process) = self.process.as_mut() else {
+      return Health::Stopped("not started".to_string());
+    };
+
+    match process.try_wait() {
+      Ok(None) => Health::Running,
+      Ok(Some(status)) => Health::Stopped(format!("exited with {status}")),
+      Err(e) => Health::Stopped(e.to_string()),
+    }
+  }
+}
+
+/// Sets `capture_start` once `outfile` has content, which is about when the
+/// recorder captured its first frame
+async fn detect_capture(
+  outfile: String,
+  capture_start: Arc<Mutex<Option<Instant>>>,
+) {
+  let begin = Instant::now();
+  while begin.elapsed() < CAPTURE_TIMEOUT {
+    if Path::new(&outfile).metadata().is_ok_and(|m| m.len() > 0) {
+      *capture_start.lock().expect("Capture start lock") = Some(Instant::now());
+      return;
+    }
+    sleep(Duration::from_millis(20)).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn outputswitch() {
+    let mut rec = RecorderConfig {
+      backend: Backend::GpuScreenRecorder,
+      command: "gpu-screen-recorder".to_string(),
+      args: vec!["-c".to_string(), "mkv".to_string()],
+      outputswitch: None,
+      stderr_filter: None,
+      replay_buffer_seconds: None,
+      pre_roll_seconds: 10,
+      ffmpeg: "ffmpeg".to_string(),
+      chapter_correction_ms: 0,
+    };
+    let args = |rec: &RecorderConfig| {
+      let mut backend = ProcessBackend::gpu_screen_recorder(
+        rec.command.clone(),
+        rec.args.clone(),
+      );
+      backend.configure(rec);
+      let command = backend.command("out.mkv");
+      let args: Vec<_> = command
+        .as_std()
+        .get_args()
+        .map(|a| a.to_string_lossy().to_string())
+        .collect();
+      args.join(" ")
+    };
+
+    // The convention of the backend
+    assert_eq!(args(&rec), "-c mkv -o out.mkv");
+    // Positional output
+    rec.outputswitch = Some(String::new());
+    assert_eq!(args(&rec), "-c mkv out.mkv");
+    rec.outputswitch = Some("-f".to_string());
+    assert_eq!(args(&rec), "-c mkv -f out.mkv");
+  }
+
+  #[tokio::test]
+  async fn stop_via_stdin() {
+    // Exits once it reads the 'q'
+    let mut backend = ProcessBackend::ffmpeg(
+      "/bin/sh".to_string(),
+      vec!["-c".to_string(), "test \"$(head -c 1)\" = q".to_string()],
+    );
+    assert_eq!(backend.health(), Health::Stopped("not started".to_string()));
+
+    backend.start("ignored").unwrap();
+    assert_eq!(backend.health(), Health::Running);
+    assert!(backend.start("ignored").is_err());
+
+    backend.stop().await.unwrap();
+    assert!(backend.stop().await.is_err());
+  }
+
+  #[tokio::test]
+  async fn capture_start() {
+    let dir = std::env::temp_dir()
+      .join(format!("progrs-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let outfile = dir.join("out.mkv").to_string_lossy().to_string();
+
+    // Starts "capturing" after 200ms, the output file is $0
+    let mut backend = ProcessBackend::ffmpeg(
+      "/bin/sh".to_string(),
+      vec![
+        "-c".to_string(),
+        "sleep 0.2; echo video > \"$0\"; head -c 1".to_string(),
+      ],
+    );
+    let start = Instant::now();
+    backend.start(&outfile).unwrap();
+    assert_eq!(backend.video_start(), None);
+
+    sleep(Duration::from_millis(500)).await;
+    let capture = backend.video_start().expect("Capture detected");
+    assert!(capture - start >= Duration::from_millis(200));
+
+    backend.stop().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn stop_via_signal() {
+    let mut backend = ProcessBackend::wf_recorder(
+      "/bin/sleep".to_string(),
+      vec!["10".to_string()],
+    );
+    // `sleep 10 -f file` fails right away, so use positional output
+    backend.outputswitch = None;
+    backend.start("20").unwrap();
+
+    // sleep does not handle SIGINT, so this is not a clean exit
+    let e = backend.stop().await.unwrap_err();
+    assert!(e.to_string().contains("signal: 2"));
+  }
+}
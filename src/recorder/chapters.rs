@@ -0,0 +1,404 @@
+use std::fmt::Write;
+
+use chrono::{NaiveDateTime, TimeDelta};
+
+use super::Segment;
+use crate::events::{Encounter, Event};
+
+/// A chapter of a recording, times are log times
+#[derive(Debug, PartialEq)]
+pub struct Chapter {
+  pub name: String,
+  pub start: NaiveDateTime,
+  /// Until the next chapter, if unset
+  pub end: Option<NaiveDateTime>,
+  /// A boss encounter, the deaths during it are its children
+  pub encounter: bool,
+  pub children: Vec<Chapter>,
+}
+
+impl Chapter {
+  fn new(name: String, start: NaiveDateTime) -> Self {
+    Self {
+      name,
+      start,
+      end: None,
+      encounter: false,
+      children: vec![],
+    }
+  }
+
+  /// The name in simple chapters, which have no hierarchy
+  fn flat_name(&self) -> String {
+    if self.encounter {
+      format!("Encounter Start: {}", self.name)
+    } else {
+      self.name.clone()
+    }
+  }
+}
+
+/// Builds the chapters from the `events` of a recording. Deaths during a boss
+/// encounter become children of the encounter's chapter.
+pub fn from_events(events: &[Event]) -> Vec<Chapter> {
+  let mut chapters = vec![];
+  let mut boss: Option<Chapter> = None;
+  let mut round = 0;
+
+  for event in events {
+    match event {
+      Event::EncounterStart(time, Encounter { name, .. }) => {
+        chapters.extend(boss.take());
+        let mut chapter = Chapter::new(name.clone(), *time);
+        chapter.encounter = true;
+        boss = Some(chapter);
+      }
+      Event::EncounterEnd(time, _) => {
+        if let Some(mut chapter) = boss.take() {
+          chapter.end = Some(*time);
+          chapters.push(chapter);
+        }
+      }
+      Event::PlayerDeath(time, name) => {
+        let death = Chapter::new(format!("Death: {name}"), *time);
+        match boss.as_mut() {
+          Some(boss) => boss.children.push(death),
+          None => chapters.push(death),
+        }
+      }
+      Event::ArenaMatchStart(time, _) => {
+        round += 1;
+        chapters.push(Chapter::new(format!("Round {round}"), *time));
+      }
+      _ => {}
+    }
+  }
+
+  chapters.extend(boss);
+  chapters
+}
+
+/// Sets the end of all `chapters` without one: the start of the next one, or
+/// `end` for the last one
+fn close(chapters: &mut [Chapter], end: NaiveDateTime) {
+  let starts: Vec<_> = chapters.iter().skip(1).map(|c| c.start).collect();
+
+  for (idx, chapter) in chapters.iter_mut().enumerate() {
+    let next = starts.get(idx).copied().unwrap_or(end);
+    let chapter_end = *chapter.end.get_or_insert(next);
+    close(&mut chapter.children, chapter_end);
+  }
+}
+
+/// The OGM style simple chapter format, a flat list of starts
+pub fn to_simple(chapters: &[Chapter], segments: &[Segment]) -> String {
+  let mut s = String::new();
+  let mut flat = vec![];
+  flatten(chapters, &mut flat);
+
+  for (idx, chapter) in flat.iter().enumerate() {
+    let tdelta = video_time(chapter.start, segments);
+    writeln!(
+      &mut s,
+      "CHAPTER{:02}={:02}:{:02}:{:02}.{:03}",
+      idx + 1,
+      tdelta.num_hours(),
+      tdelta.num_minutes() % 60,
+      tdelta.num_seconds() % 60,
+      tdelta.num_milliseconds() % 1000
+    )
+    .expect("Write into String");
+    writeln!(&mut s, "CHAPTER{:02}NAME={}", idx + 1, chapter.flat_name())
+      .expect("Write into String");
+  }
+
+  s
+}
+
+fn flatten<'a>(chapters: &'a [Chapter], flat: &mut Vec<&'a Chapter>) {
+  for chapter in chapters {
+    flat.push(chapter);
+    flatten(&chapter.children, flat);
+  }
+}
+
+/// Matroska XML chapters with end times, names are in `language` (ISO 639-2).
+/// `end` is the log time the recording ended at.
+pub fn to_xml(
+  mut chapters: Vec<Chapter>,
+  segments: &[Segment],
+  end: NaiveDateTime,
+  language: &str,
+) -> String {
+  if chapters.is_empty() {
+    return String::new();
+  }
+  close(&mut chapters, end);
+
+  let mut s = String::new();
+  s.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  s.push_str("<!DOCTYPE Chapters SYSTEM \"matroskachapters.dtd\">\n");
+  s.push_str("<Chapters>\n  <EditionEntry>\n");
+  for chapter in &chapters {
+    write_atom(&mut s, chapter, segments, language, 2);
+  }
+  s.push_str("  </EditionEntry>\n</Chapters>\n");
+
+  s
+}
+
+fn write_atom(
+  s: &mut String,
+  chapter: &Chapter,
+  segments: &[Segment],
+  language: &str,
+  depth: usize,
+) {
+  let indent = "  ".repeat(depth);
+  let start = video_time(chapter.start, segments);
+  let end = video_time(chapter.end.expect("Chapters are closed"), segments);
+
+  writeln!(s, "{indent}<ChapterAtom>").expect("Write into String");
+  writeln!(
+    s,
+    "{indent}  <ChapterTimeStart>{}</ChapterTimeStart>",
+    xml_time(start)
+  )
+  .expect("Write into String");
+  writeln!(
+    s,
+    "{indent}  <ChapterTimeEnd>{}</ChapterTimeEnd>",
+    xml_time(end)
+  )
+  .expect("Write into String");
+  writeln!(s, "{indent}  <ChapterDisplay>").expect("Write into String");
+  writeln!(
+    s,
+    "{indent}    <ChapterString>{}</ChapterString>",
+    escape(&chapter.name)
+  )
+  .expect("Write into String");
+  writeln!(
+    s,
+    "{indent}    <ChapterLanguage>{}</ChapterLanguage>",
+    escape(language)
+  )
+  .expect("Write into String");
+  writeln!(s, "{indent}  </ChapterDisplay>").expect("Write into String");
+  for child in &chapter.children {
+    write_atom(s, child, segments, language, depth + 1);
+  }
+  writeln!(s, "{indent}</ChapterAtom>").expect("Write into String");
+}
+
+/// ffmpeg's FFMETADATA with `title` and a flat list of chapters, each ending
+/// where the next one starts. `end` is the log time the recording ended at.
+pub fn to_ffmetadata(
+  chapters: &[Chapter],
+  segments: &[Segment],
+  end: NaiveDateTime,
+  title: &str,
+) -> String {
+  let mut s = String::from(";FFMETADATA1\n");
+  writeln!(s, "title={}", escape_ffmetadata(title)).expect("Write into String");
+
+  let mut flat = vec![];
+  flatten(chapters, &mut flat);
+  for (idx, chapter) in flat.iter().enumerate() {
+    let next = flat.get(idx + 1).map_or(end, |c| c.start);
+    writeln!(
+      s,
+      "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}",
+      video_time(chapter.start, segments).num_milliseconds(),
+      video_time(next, segments).num_milliseconds(),
+      escape_ffmetadata(&chapter.flat_name())
+    )
+    .expect("Write into String");
+  }
+
+  s
+}
+
+/// Escapes the characters with a special meaning in FFMETADATA
+fn escape_ffmetadata(s: &str) -> String {
+  let mut escaped = String::new();
+  for c in s.chars() {
+    if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+/// Formats `t` as HH:MM:SS.nnnnnnnnn
+fn xml_time(t: TimeDelta) -> String {
+  format!(
+    "{:02}:{:02}:{:02}.{:09}",
+    t.num_hours(),
+    t.num_minutes() % 60,
+    t.num_seconds() % 60,
+    t.subsec_nanos()
+  )
+}
+
+fn escape(s: &str) -> String {
+  s.replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+}
+
+/// The position of log time `time` in the video joined from `segments`.
+/// Times before the video start go to its beginning, times in a gap between
+/// segments to the start of the next one.
+pub fn video_time(time: NaiveDateTime, segments: &[Segment]) -> TimeDelta {
+  let mut offset = TimeDelta::zero();
+
+  for (idx, segment) in segments.iter().enumerate() {
+    let length = segment
+      .end
+      .map(|end| (end - segment.start).max(TimeDelta::zero()));
+
+    if segments.get(idx + 1).is_none_or(|next| time < next.start) {
+      let t = (time - segment.start).max(TimeDelta::zero());
+      return offset + length.map_or(t, |l| t.min(l));
+    }
+    offset += length.expect("Only the last segment is open");
+  }
+
+  offset
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::events::{Difficulty, EncounterResult};
+
+  fn at(secs: i64) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(
+      "2024-09-19 20:14:04.000",
+      "%Y-%m-%d %H:%M:%S%.3f",
+    )
+    .unwrap()
+      + TimeDelta::seconds(secs)
+  }
+
+  #[test]
+  fn segmented_video_time() {
+    // The recorder died after 10s and recorded again from 15s on
+    let segments = [
+      Segment {
+        start: at(0),
+        end: Some(at(10)),
+      },
+      Segment {
+        start: at(15),
+        end: None,
+      },
+    ];
+
+    assert_eq!(video_time(at(-1), &segments), TimeDelta::zero());
+    assert_eq!(video_time(at(5), &segments), TimeDelta::seconds(5));
+    assert_eq!(video_time(at(12), &segments), TimeDelta::seconds(10));
+    assert_eq!(video_time(at(20), &segments), TimeDelta::seconds(15));
+  }
+
+  #[test]
+  fn nested_xml() {
+    let encounter = Encounter {
+      id: 2901,
+      name: "Avanoxx".to_string(),
+      difficulty: Difficulty::MythicKeystone,
+      group_size: 5,
+      instance_id: 2660,
+    };
+    let events = [
+      Event::PlayerDeath(at(10), "Trash".to_string()),
+      Event::EncounterStart(at(60), encounter),
+      Event::PlayerDeath(at(90), "Boss".to_string()),
+      Event::EncounterEnd(
+        at(120),
+        EncounterResult {
+          id: 2901,
+          success: true,
+          duration: Duration::from_secs(60),
+        },
+      ),
+    ];
+    let segments = [Segment {
+      start: at(0),
+      end: None,
+    }];
+
+    let chapters = from_events(&events);
+    assert_eq!(chapters.len(), 2);
+    assert_eq!(chapters[1].children.len(), 1);
+    assert_eq!(
+      to_simple(&chapters, &segments),
+      "CHAPTER01=00:00:10.000\nCHAPTER01NAME=Death: Trash\n\
+       CHAPTER02=00:01:00.000\nCHAPTER02NAME=Encounter Start: Avanoxx\n\
+       CHAPTER03=00:01:30.000\nCHAPTER03NAME=Death: Boss\n"
+    );
+
+    let xml = to_xml(chapters, &segments, at(300), "eng");
+    let atom = |start, end, name, indent: &str| {
+      format!(
+        "{indent}<ChapterAtom>\n\
+         {indent}  <ChapterTimeStart>{start}</ChapterTimeStart>\n\
+         {indent}  <ChapterTimeEnd>{end}</ChapterTimeEnd>\n\
+         {indent}  <ChapterDisplay>\n\
+         {indent}    <ChapterString>{name}</ChapterString>\n\
+         {indent}    <ChapterLanguage>eng</ChapterLanguage>\n\
+         {indent}  </ChapterDisplay>\n"
+      )
+    };
+    let expected = format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <!DOCTYPE Chapters SYSTEM \"matroskachapters.dtd\">\n\
+       <Chapters>\n  <EditionEntry>\n{}    </ChapterAtom>\n{}{}      \
+       </ChapterAtom>\n    </ChapterAtom>\n  </EditionEntry>\n</Chapters>\n",
+      atom(
+        "00:00:10.000000000",
+        "00:01:00.000000000",
+        "Death: Trash",
+        "    "
+      ),
+      atom(
+        "00:01:00.000000000",
+        "00:02:00.000000000",
+        "Avanoxx",
+        "    "
+      ),
+      atom(
+        "00:01:30.000000000",
+        "00:02:00.000000000",
+        "Death: Boss",
+        "      "
+      ),
+    );
+    assert_eq!(xml, expected);
+  }
+
+  #[test]
+  fn ffmetadata() {
+    let events = [
+      Event::PlayerDeath(at(10), "Foo".to_string()),
+      Event::PlayerDeath(at(40), "Bar=Baz".to_string()),
+    ];
+    let segments = [Segment {
+      start: at(5),
+      end: None,
+    }];
+
+    assert_eq!(
+      to_ffmetadata(&from_events(&events), &segments, at(65), "+15 Key; 2"),
+      ";FFMETADATA1\ntitle=+15 Key\\; 2\n\
+       [CHAPTER]\nTIMEBASE=1/1000\nSTART=5000\nEND=35000\n\
+       title=Death: Foo\n\
+       [CHAPTER]\nTIMEBASE=1/1000\nSTART=35000\nEND=60000\n\
+       title=Death: Bar\\=Baz\n"
+    );
+  }
+}
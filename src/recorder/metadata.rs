@@ -0,0 +1,429 @@
+use std::{fs, io, path::Path};
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+use super::{
+  chapters::video_time, Activity, ActivityResult, Recording, Segment,
+};
+use crate::events::{Combatant, Event};
+
+/// Format of the log times in the sidecar
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+/// What is known about a recording, written as JSON sidecar next to the
+/// video so other tools can index it
+#[derive(Debug, Serialize)]
+pub struct Metadata {
+  /// E.g. `raid`, `mythicplus`, `solo_shuffle`
+  pub activity: &'static str,
+  pub title: String,
+  /// The boss, dungeon or zone, not set for arenas
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  /// Log time the activity started at
+  pub start: String,
+  /// The official length of the fight, key or match, if it ended as logged.
+  /// Otherwise from the start of the activity until the recording stopped.
+  pub duration_ms: i64,
+  /// E.g. `kill`, `timed`, `win`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub outcome: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub encounter_id: Option<u32>,
+  /// The instance ID of the raid, dungeon, arena or zone
+  pub instance_id: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub challenge_mode_id: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub difficulty: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub keystone_level: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub affixes: Option<Vec<u32>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub bracket: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rated: Option<bool>,
+  /// Mythic+ rating or rating of the player's arena team after the activity
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rating: Option<f64>,
+  /// Change of the Mythic+ rating
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rating_change: Option<f64>,
+  pub participants: Vec<Combatant>,
+  pub events: Vec<TimelineEvent>,
+}
+
+/// An event during a recording
+#[derive(Debug, Serialize)]
+pub struct TimelineEvent {
+  /// Log time of the event
+  pub time: String,
+  /// Position in the video
+  pub video_ms: i64,
+  #[serde(flatten)]
+  pub kind: EventKind,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+  Death {
+    name: String,
+  },
+  EncounterStart {
+    encounter_id: u32,
+    name: String,
+    difficulty: String,
+  },
+  EncounterEnd {
+    encounter_id: u32,
+    success: bool,
+    duration_ms: u128,
+  },
+  /// A new round of a Solo Shuffle
+  Round {
+    number: u32,
+  },
+}
+
+impl Metadata {
+  /// Collects the metadata of `recording` with the video joined from
+  /// `segments`. `end` is the log time the recording ended at.
+  pub fn new(
+    recording: &Recording,
+    segments: &[Segment],
+    end: NaiveDateTime,
+    title: String,
+  ) -> Self {
+    let mut metadata = Self {
+      activity: recording.activity.kind(),
+      title,
+      name: None,
+      start: recording.starttime.format(TIME_FORMAT).to_string(),
+      duration_ms: (end - recording.starttime).num_milliseconds(),
+      outcome: recording.outcome.map(|o| o.to_string()),
+      encounter_id: None,
+      instance_id: 0,
+      challenge_mode_id: None,
+      difficulty: None,
+      keystone_level: None,
+      affixes: None,
+      bracket: None,
+      rated: None,
+      rating: None,
+      rating_change: None,
+      participants: recording.participants.clone(),
+      events: timeline(&recording.events, segments),
+    };
+
+    match &recording.activity {
+      Activity::Raid(e) => {
+        metadata.name = Some(e.name.clone());
+        metadata.encounter_id = Some(e.id);
+        metadata.instance_id = e.instance_id;
+        metadata.difficulty = Some(e.difficulty.to_string());
+      }
+      Activity::MythicPlus(c) => {
+        metadata.name = Some(c.name.clone());
+        metadata.instance_id = c.map_id;
+        metadata.challenge_mode_id = Some(c.challenge_mode_id);
+        metadata.keystone_level = Some(c.keystone_level);
+        metadata.affixes = Some(c.affixes.clone());
+      }
+      Activity::Arena(a) | Activity::SoloShuffle(a) => {
+        metadata.instance_id = a.instance_id;
+        metadata.bracket = Some(a.bracket.clone());
+        metadata.rated = Some(a.rated);
+      }
+      Activity::Battleground(z) | Activity::Dungeon(z) | Activity::Delve(z) => {
+        metadata.name = Some(z.name.clone());
+        metadata.instance_id = z.instance_id;
+        metadata.difficulty = Some(z.difficulty().to_string());
+      }
+    }
+
+    let duration = match &recording.result {
+      Some(ActivityResult::Encounter(r)) => Some(r.duration),
+      Some(ActivityResult::ChallengeMode(r)) => {
+        metadata.rating = r.rating;
+        metadata.rating_change = r.rating_change;
+        Some(r.duration)
+      }
+      Some(ActivityResult::Arena(r)) => {
+        if let Activity::Arena(a) = &recording.activity {
+          let team = usize::try_from(a.team_id).ok();
+          let rating = team.and_then(|t| r.ratings.get(t));
+          metadata.rating = rating.filter(|_| a.rated).map(|&r| r.into());
+        }
+        Some(r.duration)
+      }
+      None => None,
+    };
+    if let Some(ms) = duration.and_then(|d| i64::try_from(d.as_millis()).ok())
+    {
+      metadata.duration_ms = ms;
+    }
+
+    metadata
+  }
+
+  /// Writes the metadata as JSON next to `video`, with the extension `json`
+  pub fn write(&self, video: &Path) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+    fs::write(video.with_extension("json"), json + "\n")
+  }
+}
+
+#[cfg(test)]
+impl Metadata {
+  /// A pull of the boss of `Encounter::raid_fixture`, for tests
+  pub fn raid_fixture() -> Self {
+    Self {
+      activity: "raid",
+      title: "Mythic Queen Ansurek".to_string(),
+      name: Some("Queen Ansurek".to_string()),
+      start: "2024-09-19T20:14:04.000".to_string(),
+      duration_ms: 312_000,
+      outcome: None,
+      encounter_id: Some(2922),
+      instance_id: 2657,
+      challenge_mode_id: None,
+      difficulty: Some("Mythic".to_string()),
+      keystone_level: None,
+      affixes: None,
+      bracket: None,
+      rated: None,
+      rating: None,
+      rating_change: None,
+      participants: vec![],
+      events: vec![],
+    }
+  }
+}
+
+/// The recorded `events` in a form fit for the sidecar
+fn timeline(events: &[Event], segments: &[Segment]) -> Vec<TimelineEvent> {
+  let mut round = 0;
+
+  events
+    .iter()
+    .filter_map(|event| {
+      let (time, kind) = match event {
+        Event::PlayerDeath(time, name) => {
+          (time, EventKind::Death { name: name.clone() })
+        }
+        Event::EncounterStart(time, e) => (
+          time,
+          EventKind::EncounterStart {
+            encounter_id: e.id,
+            name: e.name.clone(),
+            difficulty: e.difficulty.to_string(),
+          },
+        ),
+        Event::EncounterEnd(time, r) => (
+          time,
+          EventKind::EncounterEnd {
+            encounter_id: r.id,
+            success: r.success,
+            duration_ms: r.duration.as_millis(),
+          },
+        ),
+        Event::ArenaMatchStart(time, _) => {
+          round += 1;
+          (time, EventKind::Round { number: round })
+        }
+        _ => return None,
+      };
+
+      Some(TimelineEvent {
+        time: time.format(TIME_FORMAT).to_string(),
+        video_ms: video_time(*time, segments).num_milliseconds(),
+        kind,
+      })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, Instant};
+
+  use chrono::TimeDelta;
+  use serde_json::{json, Value};
+
+  use super::*;
+  use crate::{
+    events::{
+      ArenaMatch, ArenaResult, ChallengeMode, ChallengeModeResult, Difficulty,
+      Encounter, EncounterResult,
+    },
+    recorder::Outcome,
+  };
+
+  #[test]
+  fn mythicplus() {
+    let start = NaiveDateTime::parse_from_str(
+      "2024-09-19 20:14:04.000",
+      "%Y-%m-%d %H:%M:%S%.3f",
+    )
+    .unwrap();
+    let key = ChallengeMode {
+      name: "Ara-Kara, City of Echoes".to_string(),
+      map_id: 2660,
+      challenge_mode_id: 503,
+      keystone_level: 15,
+      affixes: vec![10, 9],
+    };
+    let mut recording = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::MythicPlus(key),
+    );
+    recording.add_participant(Combatant {
+      guid: "Player-1-2".to_string(),
+      faction: 1,
+      spec_id: 577,
+    });
+    recording.add_encounter(
+      start + TimeDelta::seconds(60),
+      Encounter {
+        id: 2901,
+        name: "Avanoxx".to_string(),
+        difficulty: Difficulty::MythicKeystone,
+        group_size: 5,
+        instance_id: 2660,
+      },
+    );
+    recording.add_death(start + TimeDelta::seconds(90), "Foo".to_string());
+    recording.end_key(ChallengeModeResult {
+      map_id: 2660,
+      success: true,
+      keystone_level: 15,
+      duration: Duration::from_millis(1_750_500),
+      rating: Some(2845.5),
+      rating_change: Some(12.5),
+    });
+    let segments = [Segment {
+      start: start + TimeDelta::seconds(1),
+      end: None,
+    }];
+
+    let end = start + TimeDelta::seconds(1800);
+    let metadata = Metadata::new(&recording, &segments, end, "+15".to_string());
+    let value: Value = serde_json::to_value(&metadata).unwrap();
+    assert_eq!(
+      value,
+      json!({
+        "activity": "mythicplus",
+        "title": "+15",
+        "name": "Ara-Kara, City of Echoes",
+        "start": "2024-09-19T20:14:04.000",
+        "duration_ms": 1_750_500,
+        "outcome": "timed",
+        "instance_id": 2660,
+        "challenge_mode_id": 503,
+        "keystone_level": 15,
+        "affixes": [10, 9],
+        "rating": 2845.5,
+        "rating_change": 12.5,
+        "participants": [
+          {"guid": "Player-1-2", "faction": 1, "spec_id": 577}
+        ],
+        "events": [
+          {
+            "time": "2024-09-19T20:15:04.000",
+            "video_ms": 59_000,
+            "type": "encounter_start",
+            "encounter_id": 2901,
+            "name": "Avanoxx",
+            "difficulty": "Mythic+"
+          },
+          {
+            "time": "2024-09-19T20:15:34.000",
+            "video_ms": 89_000,
+            "type": "death",
+            "name": "Foo"
+          }
+        ]
+      })
+    );
+  }
+
+  #[test]
+  fn official_durations() {
+    let start = NaiveDateTime::default();
+    let segments = [Segment { start, end: None }];
+    // Stopped after the post-roll
+    let end = start + TimeDelta::seconds(330);
+
+    let mut raid = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::Raid(Encounter::raid_fixture()),
+    );
+    raid.end_raid(
+      start + TimeDelta::seconds(312),
+      EncounterResult {
+        id: 2922,
+        success: true,
+        duration: Duration::from_millis(311_800),
+      },
+    );
+    let metadata = Metadata::new(&raid, &segments, end, String::new());
+    assert_eq!(metadata.outcome.as_deref(), Some("kill"));
+    assert_eq!(metadata.duration_ms, 311_800);
+    let value = serde_json::to_value(&metadata.events).unwrap();
+    assert_eq!(
+      value,
+      json!([{
+        "time": "1970-01-01T00:05:12.000",
+        "video_ms": 312_000,
+        "type": "encounter_end",
+        "encounter_id": 2922,
+        "success": true,
+        "duration_ms": 311_800
+      }])
+    );
+
+    let mut arena = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::Arena(ArenaMatch {
+        instance_id: 1505,
+        rated: true,
+        bracket: "3v3".to_string(),
+        team_id: 1,
+      }),
+    );
+    arena.end_arena(ArenaResult {
+      winning_team: 0,
+      duration: Duration::from_secs(245),
+      ratings: [1850, 1780],
+    });
+    let metadata = Metadata::new(&arena, &segments, end, String::new());
+    assert_eq!(arena.outcome, Some(Outcome::Loss));
+    assert_eq!(metadata.duration_ms, 245_000);
+    assert_eq!(metadata.rating, Some(1780.0));
+
+    // Stopped before it ended
+    let aborted = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::Arena(ArenaMatch {
+        instance_id: 1505,
+        rated: true,
+        bracket: "3v3".to_string(),
+        team_id: 1,
+      }),
+    );
+    let metadata = Metadata::new(&aborted, &segments, end, String::new());
+    assert_eq!(metadata.duration_ms, 330_000);
+    assert_eq!(metadata.rating, None);
+  }
+}
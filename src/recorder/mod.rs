@@ -0,0 +1,858 @@
+use std::{fmt::Display, path::PathBuf, time::Instant};
+
+use chrono::{NaiveDateTime, TimeDelta};
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+  config::{executable, ChapterFormat, Container, ProgrsConfig},
+  index::Index,
+  events::{
+    ArenaMatch, ArenaResult, ChallengeMode, ChallengeModeResult, Combatant,
+    Encounter, EncounterResult, Event, InstanceType, Zone,
+  },
+};
+
+pub mod backend;
+pub mod chapters;
+pub mod metadata;
+pub mod obs;
+pub mod postprocess;
+pub mod postroll;
+pub mod process;
+pub mod replaybuffer;
+pub mod retention;
+
+use backend::{Health, RecorderBackend};
+use postprocess::{segment_file, Finished, Merger};
+use retention::Retention;
+
+/// How often the recorder is restarted during one recording before giving up
+const MAX_RESTARTS: usize = 5;
+
+pub struct Recorder {
+  pub viddir: String,
+  pub backend: Box<dyn RecorderBackend>,
+  /// The container the recorder writes, determines the file extension
+  pub container: Container,
+  /// The available tools to merge chapters into finished recordings
+  pub mergers: Vec<Merger>,
+  /// Added to all chapter times
+  pub chapter_correction: TimeDelta,
+  pub chapter_format: ChapterFormat,
+  /// The language of XML chapters
+  pub chapter_language: String,
+  pub recording: Option<Recording>,
+  /// Where finished recordings are indexed, see `Index`
+  pub index: Option<PathBuf>,
+  pub retention: Retention,
+  /// Gets the results of stopping recordings
+  events: Sender<Event>,
+  /// How many recordings are being stopped or post-processed
+  processing: usize,
+}
+
+pub struct Recording {
+  /// Log time of the start of the activity
+  starttime: NaiveDateTime,
+  /// Wall clock time the start of the activity was handled, assumed to be
+  /// right when it was logged
+  wallstart: Instant,
+  filename: String,
+  /// Finished segments, the recorder was restarted after each of them
+  segments: Vec<Segment>,
+  /// Wall clock time the current segment was started
+  segment_wallstart: Instant,
+  events: Vec<Event>,
+  /// From COMBATANT_INFO, once per player
+  participants: Vec<Combatant>,
+  pub activity: Activity,
+  /// Appended to the file name when the recording is stopped
+  pub outcome: Option<Outcome>,
+  /// How the activity ended, as logged
+  result: Option<ActivityResult>,
+}
+
+/// The logged end of a recorded activity, with its official duration
+#[derive(Debug)]
+pub enum ActivityResult {
+  Encounter(EncounterResult),
+  ChallengeMode(ChallengeModeResult),
+  Arena(ArenaResult),
+}
+
+/// A part of a recording in log time. Recordings are split into segments when
+/// the recorder has to be restarted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+  pub start: NaiveDateTime,
+  /// When the recorder died, `None` for the last segment
+  pub end: Option<NaiveDateTime>,
+}
+
+pub enum Activity {
+  /// Raidboss
+  Raid(Encounter),
+  /// Mythic+ Dungeon
+  MythicPlus(ChallengeMode),
+  /// PvP arena match
+  Arena(ArenaMatch),
+  /// Solo Shuffle, all rounds in one recording
+  SoloShuffle(ArenaMatch),
+  /// Battleground, recorded until the zone is left
+  Battleground(Zone),
+  /// Dungeon without a keystone, recorded until the zone is left
+  Dungeon(Zone),
+  /// Delve, recorded until the zone is left
+  Delve(Zone),
+}
+
+impl Activity {
+  /// The activity recorded from entering `zone` until leaving it, if any.
+  /// Dungeons and delves only if they are in `instance_types`.
+  pub fn entered(zone: Zone, instance_types: &[InstanceType]) -> Option<Self> {
+    if zone.is_battleground() {
+      return Some(Self::Battleground(zone));
+    }
+
+    match zone.instance_type() {
+      Some(t) if !instance_types.contains(&t) => None,
+      Some(InstanceType::Dungeon) => Some(Self::Dungeon(zone)),
+      Some(InstanceType::Delve) => Some(Self::Delve(zone)),
+      None => None,
+    }
+  }
+
+  /// The kind of activity in the metadata sidecar
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::Raid(_) => "raid",
+      Self::MythicPlus(_) => "mythicplus",
+      Self::Arena(_) => "arena",
+      Self::SoloShuffle(_) => "solo_shuffle",
+      Self::Battleground(_) => "battleground",
+      Self::Dungeon(_) => "dungeon",
+      Self::Delve(_) => "delve",
+    }
+  }
+
+  /// Human readable description, used as the title of the video
+  pub fn title(&self) -> String {
+    match self {
+      Self::Raid(e) => format!("{} {}", e.difficulty, e.name),
+      Self::MythicPlus(c) => {
+        let affixes: Vec<String> =
+          c.affixes.iter().map(|a| a.to_string()).collect();
+        format!(
+          "+{} {} (affixes {})",
+          c.keystone_level,
+          c.name,
+          affixes.join(", ")
+        )
+      }
+      Self::Arena(a) => format!(
+        "{}rena {} (instance {})",
+        if a.rated { "Rated a" } else { "A" },
+        a.bracket,
+        a.instance_id
+      ),
+      Self::SoloShuffle(a) => {
+        format!("{} (instance {})", a.bracket, a.instance_id)
+      }
+      Self::Battleground(z) => format!("Battleground {}", z.name),
+      Self::Dungeon(z) => format!("{} {}", z.difficulty(), z.name),
+      Self::Delve(z) => format!("Delve {}", z.name),
+    }
+  }
+}
+
+/// Used in the file name of the recording
+impl Display for Activity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      match self {
+        Self::Raid(e) => {
+          write!(f, "{}_{}", e.difficulty, filename_part(&e.name))
+        }
+        Self::MythicPlus(c) => {
+          write!(f, "+{}_{}", c.keystone_level, filename_part(&c.name))
+        }
+        Self::Arena(a) => {
+          let rated = if a.rated { "Rated_" } else { "" };
+          write!(f, "{rated}Arena_{}", filename_part(&a.bracket))
+        }
+        Self::SoloShuffle(a) => write!(f, "{}", filename_part(&a.bracket)),
+        Self::Battleground(z) => {
+          write!(f, "Battleground_{}", filename_part(&z.name))
+        }
+        Self::Dungeon(z) => {
+          write!(f, "{}_{}", z.difficulty(), filename_part(&z.name))
+        }
+        Self::Delve(z) => write!(f, "Delve_{}", filename_part(&z.name)),
+      }
+    }
+}
+
+/// Makes `s` suitable as part of a file name: blanks are replaced by
+/// underscores, commas and slashes are dropped
+fn filename_part(s: &str) -> String {
+  s.chars()
+    .filter(|c| !matches!(c, ',' | '/'))
+    .map(|c| if c == ' ' { '_' } else { c })
+    .collect()
+}
+
+/// How a recorded activity ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+  Kill,
+  Wipe,
+  /// Key finished in time
+  Timed,
+  /// Key over time or abandoned
+  Depleted,
+  Win,
+  Loss,
+}
+
+impl Display for Outcome {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Kill => write!(f, "kill"),
+      Self::Wipe => write!(f, "wipe"),
+      Self::Timed => write!(f, "timed"),
+      Self::Depleted => write!(f, "depleted"),
+      Self::Win => write!(f, "win"),
+      Self::Loss => write!(f, "loss"),
+    }
+  }
+}
+
+impl Recorder {
+  pub fn new(
+    conf: &ProgrsConfig,
+    backend: Box<dyn RecorderBackend>,
+    index: Option<PathBuf>,
+    events: Sender<Event>,
+  ) -> Self {
+    let mergers = [
+      Merger::Mkvmerge(conf.mkvmerge.clone()),
+      Merger::Ffmpeg(conf.recorder.ffmpeg.clone()),
+    ];
+    let mergers = mergers
+      .into_iter()
+      .filter(|m| match m {
+        Merger::Mkvmerge(command) | Merger::Ffmpeg(command) => {
+          executable(command).is_ok()
+        }
+      })
+      .collect();
+
+    Self {
+      viddir: conf.viddir.clone(),
+      backend,
+      container: conf.recorder.container(),
+      mergers,
+      chapter_correction: TimeDelta::milliseconds(
+        conf.recorder.chapter_correction_ms,
+      ),
+      chapter_format: conf.chapter_format,
+      chapter_language: conf.chapter_language.clone(),
+      recording: None,
+      retention: Retention::new(conf, index.clone()),
+      index,
+      events,
+      processing: 0,
+    }
+  }
+
+  pub fn start_recording(&mut self, time: NaiveDateTime, activity: Activity) {
+    let wallstart = Instant::now();
+    let datetimestr = time.format("%Y%m%d_%H%M%S");
+    let filename = format!("{datetimestr}_{activity}");
+    if let Err(reason) = self.check_space() {
+      self.skip(format!("{filename}: {reason}"));
+      return;
+    }
+
+    println!("Recording into {filename}");
+    let outfile = segment_file(&self.viddir, &filename, self.container, 0);
+
+    if let Err(e) = self.backend.start(&outfile) {
+      println!("Could not start recorder: {e}");
+      return;
+    }
+
+    let recording = Recording::new(time, wallstart, filename, activity);
+    self.recording = Some(recording);
+  }
+
+  /// Checks that enough space is free for a recording, see
+  /// `Retention::make_room`
+  fn check_space(&self) -> Result<(), String> {
+    match self.retention.free_space() {
+      Ok(free) => self.retention.make_room(free),
+      Err(e) => {
+        // Rather record than miss the activity
+        let problem =
+          format!("Could not determine free space in {}: {e}", self.viddir);
+        self.send(Event::RecordingWarning(problem));
+        Ok(())
+      }
+    }
+  }
+
+  /// Reports that an activity is not recorded, for `reason`
+  fn skip(&self, reason: String) {
+    self.send(Event::RecordingSkipped(reason));
+  }
+
+  /// Sends `event` to the event loop
+  fn send(&self, event: Event) {
+    let events = self.events.clone();
+    tokio::spawn(async move {
+      events.send(event).await.expect("Event channel");
+    });
+  }
+
+  /// Adds a player death to the current recording
+  pub fn add_death(&mut self, datetime: NaiveDateTime, name: String) {
+    let marker = format!("Death: {name}");
+    let Some(recording) = self.recording.as_mut() else {
+      return;
+    };
+
+    recording.add_death(datetime, name);
+    self.add_marker(&marker);
+  }
+
+  /// Adds a boss encounter to the current recording
+  pub fn add_encounter(
+    &mut self,
+    datetime: NaiveDateTime,
+    encounter: Encounter,
+  ) {
+    let marker = format!("Encounter Start: {}", encounter.name);
+    let Some(recording) = self.recording.as_mut() else {
+      return;
+    };
+
+    recording.add_encounter(datetime, encounter);
+    self.add_marker(&marker);
+  }
+
+  /// Ends the boss encounter of the current recording
+  pub fn end_encounter(
+    &mut self,
+    datetime: NaiveDateTime,
+    result: EncounterResult,
+  ) {
+    let Some(recording) = self.recording.as_mut() else {
+      return;
+    };
+
+    recording.end_encounter(datetime, result);
+  }
+
+  /// Adds a new round of a Solo Shuffle to the current recording
+  pub fn add_round(&mut self, datetime: NaiveDateTime, arena: ArenaMatch) {
+    let Some(recording) = self.recording.as_mut() else {
+      return;
+    };
+
+    let round = recording.add_round(datetime, arena);
+    self.add_marker(&format!("Round {round}"));
+  }
+
+  /// Adds a participant to the current recording
+  pub fn add_participant(&mut self, combatant: Combatant) {
+    if let Some(recording) = self.recording.as_mut() {
+      recording.add_participant(combatant);
+    }
+  }
+
+  fn add_marker(&mut self, name: &str) {
+    if let Err(e) = self.backend.add_marker(name) {
+      println!("Could not add marker '{name}': {e}");
+    }
+  }
+
+  /// Checks whether the recorder died during a recording, returns why
+  pub fn check_health(&mut self) -> Option<String> {
+    self.recording.as_ref()?;
+
+    match self.backend.health() {
+      Health::Running => None,
+      Health::Stopped(reason) => Some(reason),
+    }
+  }
+
+  /// Restarts the recorder after it died, into a new segment of the current
+  /// recording
+  pub fn restart(&mut self) {
+    let Some(recording) = self.recording.as_ref() else {
+      return;
+    };
+
+    if recording.segments.len() >= MAX_RESTARTS {
+      println!("Recorder died {MAX_RESTARTS} times, giving up");
+      self.stop_recording();
+      return;
+    }
+
+    let segment = Segment {
+      start: self.segment_start(recording),
+      end: Some(recording.log_time(Instant::now())),
+    };
+
+    // Collect what is left of the dead recorder
+    let stop = self.backend.stop();
+    tokio::spawn(async move {
+      if let Err(e) = stop.await {
+        println!("Recorder died: {e}");
+      }
+    });
+
+    let recording = self.recording.as_mut().expect("Checked above");
+    recording.segments.push(segment);
+    recording.segment_wallstart = Instant::now();
+    let outfile = segment_file(
+      &self.viddir,
+      &recording.filename,
+      self.container,
+      recording.segments.len(),
+    );
+
+    println!("Restarting recorder into {outfile}");
+    if let Err(e) = self.backend.start(&outfile) {
+      println!("Could not restart recorder: {e}");
+    }
+  }
+
+  /// The log time the current segment of `recording` started at
+  fn segment_start(&self, recording: &Recording) -> NaiveDateTime {
+    // Without knowing better, assume the recorder started capturing at once
+    let wall = self
+      .backend
+      .video_start()
+      .unwrap_or(recording.segment_wallstart);
+    recording.log_time(wall) - self.chapter_correction
+  }
+
+  pub fn stop_recording(&mut self) {
+    let Some(recording) = self.recording.take() else {
+      println!("Not recording, can't stop it");
+      return;
+    };
+
+    let mut segments = recording.segments.clone();
+    segments.push(Segment {
+      start: self.segment_start(&recording),
+      end: None,
+    });
+    let title = match recording.outcome {
+      Some(outcome) => format!("{}, {outcome}", recording.activity.title()),
+      None => recording.activity.title(),
+    };
+    let end = recording.log_time(Instant::now());
+    let mut finished = Finished {
+      viddir: self.viddir.clone(),
+      filename: recording.filename.clone(),
+      container: self.container,
+      segments: segments.into_iter().enumerate().collect(),
+      recording,
+      title,
+      end,
+      chapter_format: self.chapter_format,
+      chapter_language: self.chapter_language.clone(),
+    };
+    let stop = self.backend.stop();
+    let mergers = self.mergers.clone();
+    let index = self.index.clone();
+    let events = self.events.clone();
+    self.processing += 1;
+
+    tokio::spawn(async move {
+      let event = match stop.await {
+        Err(e) => Event::RecordingFailed(finished.filename, e),
+        Ok(()) => {
+          finished.detect_container();
+          finished.drop_unwritten();
+          finished.tag();
+          let metadata = finished.metadata();
+          match finished.merge(&mergers).await {
+            Ok(file) => {
+              if let Err(e) = metadata.write(&file) {
+                println!("Could not write sidecar of {}: {e}", file.display());
+              }
+              if let Some(index) = index {
+                let added = Index::open(&index)
+                  .and_then(|i| i.add(&file, &metadata));
+                match added {
+                  Ok(Some(pull)) => println!("Pull {pull} of this boss"),
+                  Ok(None) => {}
+                  Err(e) => println!("Could not index {}: {e}", file.display()),
+                }
+              }
+              Event::RecordingSaved(file)
+            }
+            Err(e) => Event::RecordingFailed(finished.filename, e),
+          }
+        }
+      };
+      events.send(event).await.expect("Event channel");
+    });
+  }
+
+  /// A recording was post-processed, see `Event::RecordingSaved` and
+  /// `Event::RecordingFailed`
+  pub fn processed(&mut self) {
+    self.processing = self.processing.saturating_sub(1);
+  }
+
+  /// Deletes old recordings according to the retention rules
+  pub fn cleanup(&self) {
+    match self.retention.enforce() {
+      Ok(deleted) => {
+        for video in deleted {
+          println!("Deleted old recording {}", video.display());
+        }
+      }
+      Err(e) => println!("Could not clean up old recordings: {e}"),
+    }
+  }
+
+  /// Whether recordings are still being stopped or post-processed
+  pub fn is_processing(&self) -> bool {
+    self.processing > 0
+  }
+}
+
+impl Recording {
+  pub fn new(
+    starttime: NaiveDateTime,
+    wallstart: Instant,
+    filename: String,
+    activity: Activity
+  ) -> Self {
+    Self {
+      starttime,
+      wallstart,
+      filename,
+      segments: vec![],
+      segment_wallstart: wallstart,
+      events: vec![],
+      participants: vec![],
+      activity,
+      outcome: None,
+      result: None,
+    }
+  }
+
+  pub fn is_raid(&self) -> bool {
+    matches!(self.activity, Activity::Raid(_))
+  }
+
+  pub fn is_mythicplus(&self) -> bool {
+    matches!(self.activity, Activity::MythicPlus(_))
+  }
+
+  pub fn is_solo_shuffle(&self) -> bool {
+    matches!(self.activity, Activity::SoloShuffle(_))
+  }
+
+  pub fn is_dungeon(&self) -> bool {
+    matches!(self.activity, Activity::Dungeon(_))
+  }
+
+  pub fn is_delve(&self) -> bool {
+    matches!(self.activity, Activity::Delve(_))
+  }
+
+  /// Whether boss encounters are chapters of this recording instead of
+  /// recordings of their own
+  pub fn has_encounters(&self) -> bool {
+    self.is_mythicplus() || self.is_dungeon() || self.is_delve()
+  }
+
+  /// Returns the zone this recording lasts for, if it is stopped by leaving
+  /// the zone
+  pub fn zone(&self) -> Option<&Zone> {
+    match &self.activity {
+      Activity::Battleground(z) | Activity::Dungeon(z) | Activity::Delve(z) => {
+        Some(z)
+      }
+      _ => None,
+    }
+  }
+
+  /// Whether `event` ends this recording: leaving its zone, or starting a
+  /// keystone in a dungeon, which is recorded on its own
+  pub fn ended_by(&self, event: &Event) -> bool {
+    match event {
+      Event::ZoneChange(_, zone) => {
+        self.zone().is_some_and(|z| z.instance_id != zone.instance_id)
+      }
+      Event::ChallengeModeStart(..) => self.is_dungeon(),
+      _ => false,
+    }
+  }
+
+  pub fn add_death(&mut self, datetime: NaiveDateTime, name: String) {
+    self.events.push(Event::PlayerDeath(datetime, name));
+  }
+
+  pub fn add_encounter(
+    &mut self,
+    datetime: NaiveDateTime,
+    encounter: Encounter,
+  ) {
+    self.events.push(Event::EncounterStart(datetime, encounter));
+  }
+
+  pub fn end_encounter(
+    &mut self,
+    datetime: NaiveDateTime,
+    result: EncounterResult,
+  ) {
+    self.events.push(Event::EncounterEnd(datetime, result));
+  }
+
+  /// Ends a raid boss recording with the logged `result`
+  pub fn end_raid(&mut self, datetime: NaiveDateTime, result: EncounterResult) {
+    self.outcome = Some(if result.success {
+      Outcome::Kill
+    } else {
+      Outcome::Wipe
+    });
+    self.events.push(Event::EncounterEnd(datetime, result.clone()));
+    self.result = Some(ActivityResult::Encounter(result));
+  }
+
+  /// Ends a Mythic+ recording with the logged `result`
+  pub fn end_key(&mut self, result: ChallengeModeResult) {
+    self.outcome = Some(if result.success {
+      Outcome::Timed
+    } else {
+      Outcome::Depleted
+    });
+    self.result = Some(ActivityResult::ChallengeMode(result));
+  }
+
+  /// Ends an arena or Solo Shuffle recording with the logged `result`
+  pub fn end_arena(&mut self, result: ArenaResult) {
+    // The team changes every Solo Shuffle round, no sensible outcome there
+    if let Activity::Arena(arena) = &self.activity {
+      self.outcome = Some(if result.winning_team == arena.team_id {
+        Outcome::Win
+      } else {
+        Outcome::Loss
+      });
+    }
+    self.result = Some(ActivityResult::Arena(result));
+  }
+
+  /// Adds a new round of a Solo Shuffle. Returns its number, as in the
+  /// chapters.
+  pub fn add_round(
+    &mut self,
+    datetime: NaiveDateTime,
+    arena: ArenaMatch,
+  ) -> usize {
+    self.events.push(Event::ArenaMatchStart(datetime, arena));
+    self
+      .events
+      .iter()
+      .filter(|e| matches!(e, Event::ArenaMatchStart(..)))
+      .count()
+  }
+
+  /// Adds a participant, unless they are known already. Solo Shuffle logs all
+  /// of them every round.
+  pub fn add_participant(&mut self, combatant: Combatant) {
+    if !self.participants.iter().any(|p| p.guid == combatant.guid) {
+      self.participants.push(combatant);
+    }
+  }
+
+  /// Maps the wall clock time `wall` to the log time
+  pub fn log_time(&self, wall: Instant) -> NaiveDateTime {
+    match wall.checked_duration_since(self.wallstart) {
+      Some(d) => self.starttime + d,
+      None => self.starttime - (self.wallstart - wall),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn activity_names() {
+    let raid = Activity::Raid(Encounter::raid_fixture());
+    assert_eq!(raid.to_string(), "Mythic_Queen_Ansurek");
+    assert_eq!(raid.title(), "Mythic Queen Ansurek");
+
+    let key = Activity::MythicPlus(ChallengeMode {
+      name: "Ara-Kara, City of Echoes".to_string(),
+      map_id: 2660,
+      challenge_mode_id: 503,
+      keystone_level: 15,
+      affixes: vec![10, 9, 152],
+    });
+    assert_eq!(key.to_string(), "+15_Ara-Kara_City_of_Echoes");
+    assert_eq!(
+      key.title(),
+      "+15 Ara-Kara, City of Echoes (affixes 10, 9, 152)"
+    );
+
+    let arena = Activity::Arena(ArenaMatch {
+      instance_id: 1505,
+      rated: true,
+      bracket: "3v3".to_string(),
+      team_id: 1,
+    });
+    assert_eq!(arena.to_string(), "Rated_Arena_3v3");
+    assert_eq!(arena.title(), "Rated arena 3v3 (instance 1505)");
+  }
+
+  #[test]
+  fn chapters_after_video_start() {
+    let start = NaiveDateTime::parse_from_str(
+      "2024-09-19 20:14:04.000",
+      "%Y-%m-%d %H:%M:%S%.3f",
+    )
+    .unwrap();
+    let wallstart = Instant::now();
+    let zone = Zone {
+      instance_id: 2652,
+      name: "The Stonevault".to_string(),
+      difficulty_id: 2,
+    };
+    let mut recording = Recording::new(
+      start,
+      wallstart,
+      "file".to_string(),
+      Activity::Dungeon(zone),
+    );
+    recording.add_death(start, "Foo".to_string());
+    recording.add_death(start + TimeDelta::seconds(65), "Bar".to_string());
+
+    // The recorder took 1.5s to start capturing
+    let capture = wallstart + std::time::Duration::from_millis(1500);
+    let videostart = recording.log_time(capture);
+    assert_eq!(videostart, start + TimeDelta::milliseconds(1500));
+    let segments = [Segment {
+      start: videostart,
+      end: None,
+    }];
+    assert_eq!(
+      chapters::to_simple(&chapters::from_events(&recording.events), &segments),
+      "CHAPTER01=00:00:00.000\nCHAPTER01NAME=Death: Foo\n\
+       CHAPTER02=00:01:03.500\nCHAPTER02NAME=Death: Bar\n"
+    );
+  }
+
+  fn zone(instance_id: u32, name: &str, difficulty_id: u32) -> Zone {
+    Zone {
+      instance_id,
+      name: name.to_string(),
+      difficulty_id,
+    }
+  }
+
+  #[test]
+  fn instance_types() {
+    let dungeon = zone(2652, "The Stonevault", 2);
+    let delve = zone(2681, "Kriegval's Rest", 208);
+    let battleground = zone(2107, "Arathi Basin", 0);
+    let raid = zone(2657, "Nerub-ar Palace", 16);
+
+    assert!(Activity::entered(dungeon.clone(), &[]).is_none());
+    assert!(Activity::entered(delve.clone(), &[]).is_none());
+    assert!(matches!(
+      Activity::entered(battleground, &[]),
+      Some(Activity::Battleground(_))
+    ));
+
+    let dungeons = [InstanceType::Dungeon];
+    assert!(matches!(
+      Activity::entered(dungeon, &dungeons),
+      Some(Activity::Dungeon(_))
+    ));
+    assert!(Activity::entered(delve.clone(), &dungeons).is_none());
+    assert!(matches!(
+      Activity::entered(delve, &[InstanceType::Delve]),
+      Some(Activity::Delve(_))
+    ));
+
+    let all = [InstanceType::Dungeon, InstanceType::Delve];
+    assert!(Activity::entered(raid, &all).is_none());
+  }
+
+  #[test]
+  fn zone_recording_ends() {
+    let start = NaiveDateTime::default();
+    let dungeon = zone(2652, "The Stonevault", 2);
+    let recording = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::Dungeon(dungeon.clone()),
+    );
+
+    // Zone changes within the dungeon, e.g. after a reload, keep recording
+    assert!(!recording.ended_by(&Event::ZoneChange(start, dungeon)));
+    let outside = zone(2552, "Khaz Algar", 0);
+    assert!(recording.ended_by(&Event::ZoneChange(start, outside.clone())));
+
+    let key = ChallengeMode {
+      name: "The Stonevault".to_string(),
+      map_id: 2652,
+      challenge_mode_id: 501,
+      keystone_level: 10,
+      affixes: vec![],
+    };
+    assert!(recording.ended_by(&Event::ChallengeModeStart(start, key.clone())));
+
+    // Only zone recordings end with the zone, only dungeons with a key
+    let delve = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::Delve(zone(2681, "Kriegval's Rest", 208)),
+    );
+    assert!(!delve.ended_by(&Event::ChallengeModeStart(start, key.clone())));
+    let mythicplus = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::MythicPlus(key),
+    );
+    assert!(!mythicplus.ended_by(&Event::ZoneChange(start, outside)));
+  }
+
+  #[test]
+  fn round_markers() {
+    let start = NaiveDateTime::default();
+    let shuffle = ArenaMatch {
+      instance_id: 1505,
+      rated: true,
+      bracket: "Rated Solo Shuffle".to_string(),
+      team_id: 0,
+    };
+    let mut recording = Recording::new(
+      start,
+      Instant::now(),
+      "file".to_string(),
+      Activity::SoloShuffle(shuffle.clone()),
+    );
+    assert_eq!(recording.add_round(start, shuffle.clone()), 1);
+    recording.add_death(start, "Foo".to_string());
+    let second = start + TimeDelta::seconds(120);
+    assert_eq!(recording.add_round(second, shuffle), 2);
+
+    // The markers are named like the chapters
+    let chapters = chapters::from_events(&recording.events);
+    assert_eq!(chapters[2].name, "Round 2");
+  }
+}
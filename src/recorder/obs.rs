@@ -0,0 +1,506 @@
+use std::{
+  collections::HashMap,
+  io,
+  path::Path,
+  sync::{Arc, Mutex},
+  time::Instant,
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures_util::{future::BoxFuture, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::{
+  fs,
+  net::TcpStream,
+  sync::{mpsc, oneshot},
+};
+use tokio_tungstenite::{
+  connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream,
+};
+
+use super::backend::{Health, RecorderBackend};
+use crate::config::{Container, ObsConfig};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Responder = oneshot::Sender<io::Result<Value>>;
+type CaptureStart = Arc<Mutex<Option<Instant>>>;
+
+/// obs-websocket v5 opcodes
+const OP_HELLO: u64 = 0;
+const OP_IDENTIFY: u64 = 1;
+const OP_IDENTIFIED: u64 = 2;
+const OP_EVENT: u64 = 5;
+const OP_REQUEST: u64 = 6;
+const OP_REQUEST_RESPONSE: u64 = 7;
+
+/// Subscribe to output events only, for RecordStateChanged
+const EVENTS_OUTPUTS: u64 = 1 << 6;
+
+/// A request to OBS, sent through the connection task
+struct Request {
+  request_type: &'static str,
+  data: Value,
+  /// Gets the responseData, if somebody is interested in it
+  respond: Option<Responder>,
+}
+
+/// Records by remote controlling OBS via obs-websocket. OBS decides about the
+/// file name, the recording is moved to the expected place when stopped.
+pub struct ObsBackend {
+  address: String,
+  password: Option<String>,
+  markers: bool,
+  connection: Option<mpsc::UnboundedSender<Request>>,
+  health: Arc<Mutex<Health>>,
+  /// When OBS confirmed the start of the recording
+  capture_start: CaptureStart,
+  outfile: Option<String>,
+}
+
+impl ObsBackend {
+  pub fn new(conf: &ObsConfig) -> Self {
+    Self {
+      address: conf.address.clone(),
+      password: conf.password.clone(),
+      markers: conf.markers,
+      connection: None,
+      health: Arc::new(Mutex::new(Health::Stopped("not started".to_string()))),
+      capture_start: Arc::default(),
+      outfile: None,
+    }
+  }
+
+  /// Sends a request to OBS, (re)connecting if necessary
+  fn request(
+    &mut self,
+    request_type: &'static str,
+    data: Value,
+    respond: Option<Responder>,
+  ) {
+    let request = Request {
+      request_type,
+      data,
+      respond,
+    };
+
+    let request = match &self.connection {
+      Some(connection) => match connection.send(request) {
+        Ok(()) => return,
+        // Connection task is gone, reconnect
+        Err(mpsc::error::SendError(request)) => request,
+      },
+      None => request,
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    tx.send(request).expect("Receiver is alive");
+    tokio::spawn(connection(
+      self.address.clone(),
+      self.password.clone(),
+      rx,
+      self.health.clone(),
+      self.capture_start.clone(),
+    ));
+    self.connection = Some(tx);
+  }
+}
+
+impl RecorderBackend for ObsBackend {
+  fn start(&mut self, outfile: &str) -> io::Result<()> {
+    if self.outfile.is_some() {
+      return Err(io::Error::other("Recorder is already running"));
+    }
+
+    *self.health.lock().expect("Health lock") = Health::Running;
+    *self.capture_start.lock().expect("Capture start lock") = None;
+    self.request("StartRecord", json!({}), None);
+    self.outfile = Some(outfile.to_string());
+    Ok(())
+  }
+
+  fn stop(&mut self) -> BoxFuture<'static, io::Result<()>> {
+    let outfile = self.outfile.take();
+    let (tx, rx) = oneshot::channel();
+    if outfile.is_some() {
+      self.request("StopRecord", json!({}), Some(tx));
+    }
+
+    Box::pin(async move {
+      let Some(outfile) = outfile else {
+        return Err(io::Error::other("Recorder is not running"));
+      };
+
+      let response = rx
+        .await
+        .map_err(|_| io::Error::other("Connection to OBS lost"))??;
+      let Some(path) = response["outputPath"].as_str() else {
+        return Err(io::Error::other("OBS did not report the output path"));
+      };
+
+      // OBS chooses the container, keep its extension
+      let extension = Path::new(path).extension().unwrap_or_default();
+      let target = Path::new(&outfile).with_extension(extension);
+      println!("OBS recorded into {path}, moving to {}", target.display());
+      // Renaming fails across file systems
+      if fs::rename(path, &target).await.is_err() {
+        fs::copy(path, &target).await?;
+        fs::remove_file(path).await?;
+      }
+
+      let extension = extension.to_string_lossy();
+      if Container::from_extension(&extension).is_none() {
+        return Err(io::Error::other(format!(
+          "OBS recorded into {extension}, which can't be post-processed"
+        )));
+      }
+      Ok(())
+    })
+  }
+
+  fn add_marker(&mut self, name: &str) -> io::Result<()> {
+    if self.markers && self.outfile.is_some() {
+      self.request(
+        "CreateRecordChapter",
+        json!({ "chapterName": name }),
+        None,
+      );
+    }
+    Ok(())
+  }
+
+  fn health(&mut self) -> Health {
+    self.health.lock().expect("Health lock").clone()
+  }
+
+  fn video_start(&self) -> Option<Instant> {
+    *self.capture_start.lock().expect("Capture start lock")
+  }
+}
+
+/// Owns the WebSocket, forwards `requests` to OBS and dispatches the
+/// responses. Ends when the connection is lost or the backend is dropped.
+async fn connection(
+  address: String,
+  password: Option<String>,
+  mut requests: mpsc::UnboundedReceiver<Request>,
+  health: Arc<Mutex<Health>>,
+  capture_start: CaptureStart,
+) {
+  let set_health = |h| *health.lock().expect("Health lock") = h;
+
+  let mut socket = match connect(&address, password.as_deref()).await {
+    Ok(s) => s,
+    Err(e) => {
+      println!("Could not connect to OBS at {address}: {e}");
+      set_health(Health::Stopped(format!("OBS not reachable: {e}")));
+      while let Ok(request) = requests.try_recv() {
+        if let Some(respond) = request.respond {
+          let _ = respond.send(Err(io::Error::other(e.to_string())));
+        }
+      }
+      return;
+    }
+  };
+
+  let mut pending: HashMap<String, (&str, Option<Responder>)> = HashMap::new();
+  let mut next_id: u64 = 0;
+
+  loop {
+    tokio::select! {
+      request = requests.recv() => {
+        let Some(request) = request else {
+          return;
+        };
+
+        next_id += 1;
+        let id = next_id.to_string();
+        let message = json!({
+          "op": OP_REQUEST,
+          "d": {
+            "requestType": request.request_type,
+            "requestId": id,
+            "requestData": request.data,
+          }
+        });
+
+        if let Err(e) = socket.send(Message::text(message.to_string())).await {
+          if let Some(respond) = request.respond {
+            let _ = respond.send(Err(io::Error::other(e.to_string())));
+          }
+          continue;
+        }
+        pending.insert(id, (request.request_type, request.respond));
+      }
+      message = socket.next() => {
+        let text = match message {
+          Some(Ok(Message::Text(text))) => text,
+          Some(Ok(Message::Close(_))) | None => {
+            set_health(Health::Stopped("OBS closed the connection".into()));
+            return;
+          }
+          Some(Err(e)) => {
+            set_health(Health::Stopped(format!("Connection to OBS lost: {e}")));
+            return;
+          }
+          Some(Ok(_)) => continue,
+        };
+
+        let Ok(message) = serde_json::from_str::<Value>(&text) else {
+          println!("Invalid message from OBS: {text}");
+          continue;
+        };
+        let d = &message["d"];
+
+        match message["op"].as_u64() {
+          Some(OP_REQUEST_RESPONSE) => {
+            let id = d["requestId"].as_str().unwrap_or_default();
+            let Some((request_type, respond)) = pending.remove(id) else {
+              continue;
+            };
+
+            let result = if d["requestStatus"]["result"] == true {
+              Ok(d["responseData"].clone())
+            } else {
+              Err(io::Error::other(format!(
+                "OBS request {request_type} failed: {}",
+                d["requestStatus"]["comment"].as_str().unwrap_or("no reason")
+              )))
+            };
+
+            match (respond, result) {
+              (Some(respond), result) => {
+                let _ = respond.send(result);
+              }
+              (None, Err(e)) => {
+                println!("{e}");
+                if request_type == "StartRecord" {
+                  set_health(Health::Stopped(e.to_string()));
+                }
+              }
+              (None, Ok(_)) => {
+                if request_type == "StartRecord" {
+                  *capture_start.lock().expect("Capture start lock") =
+                    Some(Instant::now());
+                }
+              }
+            }
+          }
+          Some(OP_EVENT) if d["eventType"] == "RecordStateChanged" => {
+            let state = d["eventData"]["outputState"].as_str().unwrap_or("");
+            if state == "OBS_WEBSOCKET_OUTPUT_STOPPED" {
+              set_health(Health::Stopped("OBS stopped recording".into()));
+            } else if state == "OBS_WEBSOCKET_OUTPUT_STARTED" {
+              set_health(Health::Running);
+            }
+          }
+          _ => {}
+        }
+      }
+    }
+  }
+}
+
+/// Connects to obs-websocket and does the handshake
+async fn connect(address: &str, password: Option<&str>) -> io::Result<Socket> {
+  let (mut socket, _) = connect_async(format!("ws://{address}"))
+    .await
+    .map_err(io::Error::other)?;
+
+  let hello = receive(&mut socket, OP_HELLO).await?;
+  let mut identify = json!({
+    "rpcVersion": 1,
+    "eventSubscriptions": EVENTS_OUTPUTS,
+  });
+
+  let auth = &hello["authentication"];
+  if !auth.is_null() {
+    let Some(password) = password else {
+      return Err(io::Error::other("OBS requires a password"));
+    };
+    identify["authentication"] = authentication(
+      password,
+      auth["salt"].as_str().unwrap_or_default(),
+      auth["challenge"].as_str().unwrap_or_default(),
+    )
+    .into();
+  }
+
+  let identify = json!({ "op": OP_IDENTIFY, "d": identify });
+  socket
+    .send(Message::text(identify.to_string()))
+    .await
+    .map_err(io::Error::other)?;
+  receive(&mut socket, OP_IDENTIFIED).await?;
+
+  Ok(socket)
+}
+
+/// Receives the next message, which has to have opcode `op`. Returns its data.
+async fn receive(socket: &mut Socket, op: u64) -> io::Result<Value> {
+  let message = match socket.next().await {
+    Some(Ok(Message::Text(text))) => text,
+    // OBS closes the connection with a reason on failed authentication
+    Some(Ok(Message::Close(Some(frame)))) => {
+      return Err(io::Error::other(format!("OBS: {}", frame.reason)));
+    }
+    Some(Err(e)) => return Err(io::Error::other(e)),
+    _ => return Err(io::Error::other("OBS closed the connection")),
+  };
+
+  let mut message: Value =
+    serde_json::from_str(&message).map_err(io::Error::other)?;
+  if message["op"].as_u64() != Some(op) {
+    return Err(io::Error::other(format!("Unexpected message: {message}")));
+  }
+
+  Ok(message["d"].take())
+}
+
+/// The authentication string of obs-websocket v5
+fn authentication(password: &str, salt: &str, challenge: &str) -> String {
+  let secret = BASE64.encode(Sha256::digest(format!("{password}{salt}")));
+  BASE64.encode(Sha256::digest(format!("{secret}{challenge}")))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs};
+
+  use tokio::net::TcpListener;
+  use tokio_tungstenite::accept_async;
+
+  use super::*;
+
+  type ServerSocket = WebSocketStream<TcpStream>;
+
+  async fn send(socket: &mut ServerSocket, message: Value) {
+    socket.send(Message::text(message.to_string())).await.unwrap();
+  }
+
+  async fn recv(socket: &mut ServerSocket) -> Value {
+    let message = socket.next().await.unwrap().unwrap();
+    serde_json::from_str(message.to_text().unwrap()).unwrap()
+  }
+
+  /// Answers the request `request_type` from the client
+  async fn respond(
+    socket: &mut ServerSocket,
+    request_type: &str,
+    response: Value,
+  ) {
+    let request = recv(socket).await;
+    assert_eq!(request["op"], OP_REQUEST);
+    assert_eq!(request["d"]["requestType"], request_type);
+
+    send(
+      socket,
+      json!({
+        "op": OP_REQUEST_RESPONSE,
+        "d": {
+          "requestType": request_type,
+          "requestId": request["d"]["requestId"],
+          "requestStatus": { "result": true, "code": 100 },
+          "responseData": response,
+        }
+      }),
+    )
+    .await;
+  }
+
+  #[test]
+  fn auth_string() {
+    // Example from the obs-websocket protocol documentation
+    assert_eq!(
+      authentication(
+        "supersecretpassword",
+        "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=",
+        "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY="
+      ),
+      "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+    );
+  }
+
+  #[tokio::test]
+  async fn mock_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let dir =
+      env::temp_dir().join(format!("progrs-obs-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // OBS records mp4, regardless of the extension progrs asks for
+    let obsfile = dir.join("obs.mp4");
+    let outfile = dir.join("out.mkv");
+    fs::write(&obsfile, "video").unwrap();
+
+    let server = tokio::spawn({
+      let obsfile = obsfile.to_string_lossy().to_string();
+      async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = accept_async(stream).await.unwrap();
+
+        send(
+          &mut socket,
+          json!({
+            "op": OP_HELLO,
+            "d": {
+              "rpcVersion": 1,
+              "authentication": { "salt": "salt", "challenge": "challenge" },
+            }
+          }),
+        )
+        .await;
+
+        let identify = recv(&mut socket).await;
+        assert_eq!(identify["op"], OP_IDENTIFY);
+        assert_eq!(
+          identify["d"]["authentication"],
+          authentication("secret", "salt", "challenge")
+        );
+        send(
+          &mut socket,
+          json!({ "op": OP_IDENTIFIED, "d": { "negotiatedRpcVersion": 1 } }),
+        )
+        .await;
+
+        respond(&mut socket, "StartRecord", json!({})).await;
+        let chapter = recv(&mut socket).await;
+        assert_eq!(chapter["d"]["requestType"], "CreateRecordChapter");
+        assert_eq!(chapter["d"]["requestData"]["chapterName"], "Death: Foo");
+        respond(&mut socket, "StopRecord", json!({ "outputPath": obsfile }))
+          .await;
+      }
+    });
+
+    let mut backend = ObsBackend::new(&ObsConfig {
+      address,
+      password: Some("secret".to_string()),
+      markers: true,
+    });
+    backend.start(&outfile.to_string_lossy()).unwrap();
+    assert_eq!(backend.health(), Health::Running);
+    backend.add_marker("Death: Foo").unwrap();
+    backend.stop().await.unwrap();
+    server.await.unwrap();
+    assert!(backend.video_start().is_some());
+
+    assert!(!obsfile.exists());
+    assert!(!outfile.exists());
+    let moved = dir.join("out.mp4");
+    assert_eq!(fs::read_to_string(moved).unwrap(), "video");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn unreachable() {
+    // Nothing listens on port 1
+    let mut backend = ObsBackend::new(&ObsConfig {
+      address: "127.0.0.1:1".to_string(),
+      password: None,
+      markers: false,
+    });
+    backend.start("out.mkv").unwrap();
+    assert!(backend.stop().await.is_err());
+    assert!(matches!(backend.health(), Health::Stopped(_)));
+  }
+}
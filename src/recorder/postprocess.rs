@@ -0,0 +1,427 @@
+use std::{
+  fs::{self, remove_file},
+  io,
+  path::{Path, PathBuf},
+  process::Stdio,
+  time::Duration,
+};
+
+use chrono::NaiveDateTime;
+use tokio::process::Command;
+
+use super::{chapters, metadata::Metadata, process::run, Recording, Segment};
+use crate::config::{ChapterFormat, Container};
+
+/// How long mkvmerge or ffmpeg get to merge chapters into a recording
+const MERGE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The tool merging the title and chapters into finished recordings
+#[derive(Clone, Debug, PartialEq)]
+pub enum Merger {
+  /// For Matroska
+  Mkvmerge(String),
+  /// For MP4 and MOV, with FFMETADATA chapters
+  Ffmpeg(String),
+}
+
+impl Merger {
+  fn handles(&self, container: Container) -> bool {
+    match self {
+      Self::Mkvmerge(_) => container == Container::Matroska,
+      Self::Ffmpeg(_) => container != Container::Matroska,
+    }
+  }
+}
+
+/// A stopped recording, to be post-processed
+pub struct Finished {
+  pub viddir: String,
+  pub filename: String,
+  /// The container the recorder was asked for, see `detect_container`
+  pub container: Container,
+  /// The segments with the index of their file, see `segment_file`
+  pub segments: Vec<(usize, Segment)>,
+  pub recording: Recording,
+  pub title: String,
+  /// The log time the recording ended at
+  pub end: NaiveDateTime,
+  /// For mkvmerge, ffmpeg only understands FFMETADATA
+  pub chapter_format: ChapterFormat,
+  pub chapter_language: String,
+}
+
+impl Finished {
+  fn segment_file(&self, idx: usize) -> String {
+    segment_file(&self.viddir, &self.filename, self.container, idx)
+  }
+
+  /// The log times of the segments, for the chapters and the timeline
+  fn segment_times(&self) -> Vec<Segment> {
+    self.segments.iter().map(|(_, segment)| *segment).collect()
+  }
+
+  /// Takes the container of the first written segment, in case the recorder
+  /// chose another one than asked for
+  pub fn detect_container(&mut self) {
+    let written = Container::ALL.into_iter().find(|c| {
+      self.segments.iter().any(|(idx, _)| {
+        let file = segment_file(&self.viddir, &self.filename, *c, *idx);
+        Path::new(&file).exists()
+      })
+    });
+    if let Some(container) = written {
+      self.container = container;
+    }
+  }
+
+  /// Forgets the segments without a file. The recorder might have died
+  /// before writing anything, the video does not contain them then.
+  pub fn drop_unwritten(&mut self) {
+    let (viddir, filename) = (&self.viddir, &self.filename);
+    let container = self.container;
+    self.segments.retain(|(idx, _)| {
+      let file = segment_file(viddir, filename, container, *idx);
+      let written = Path::new(&file).exists();
+      if !written {
+        println!("Recorder did not write {file}, leaving it out");
+      }
+      written
+    });
+  }
+
+  /// The metadata of the recording, written as sidecar next to the finished
+  /// file
+  pub fn metadata(&self) -> Metadata {
+    let segments = self.segment_times();
+    Metadata::new(&self.recording, &segments, self.end, self.title.clone())
+  }
+
+  /// The contents of the chapter file for `merger` and its extension
+  fn chapter_file(&self, merger: &Merger) -> (String, &'static str) {
+    let chapters = chapters::from_events(&self.recording.events);
+    let segments = &self.segment_times();
+    match (merger, self.chapter_format) {
+      (Merger::Ffmpeg(_), _) => (
+        chapters::to_ffmetadata(&chapters, segments, self.end, &self.title),
+        "ffmeta",
+      ),
+      (_, ChapterFormat::Simple) => {
+        (chapters::to_simple(&chapters, segments), "txt")
+      }
+      (_, ChapterFormat::Xml) => {
+        let language = &self.chapter_language;
+        (chapters::to_xml(chapters, segments, self.end, language), "xml")
+      }
+    }
+  }
+
+  /// Appends the outcome to the file names of all segments. Keeps the old
+  /// name if that fails.
+  pub fn tag(&mut self) {
+    let Some(outcome) = self.recording.outcome else {
+      return;
+    };
+
+    let tagged = format!("{}_{outcome}", self.filename);
+    for &(idx, _) in &self.segments {
+      let segment = self.segment_file(idx);
+      let target = segment_file(&self.viddir, &tagged, self.container, idx);
+      if let Err(e) = fs::rename(&segment, target) {
+        println!("Could not tag {} with '{outcome}': {e}", self.filename);
+        return;
+      }
+    }
+    self.filename = tagged;
+  }
+
+  /// Merges title and chapters into the recording with the one of `mergers`
+  /// handling its container, if any, joining its segments. Returns the path
+  /// of the finished file.
+  pub async fn merge(&self, mergers: &[Merger]) -> io::Result<PathBuf> {
+    let segments: Vec<_> = self
+      .segments
+      .iter()
+      .map(|(idx, _)| self.segment_file(*idx))
+      .collect();
+    let Some(first) = segments.first() else {
+      return Err(io::Error::other("Recorder did not write any file"));
+    };
+    let Some(merger) = mergers.iter().find(|m| m.handles(self.container))
+    else {
+      if segments.len() > 1 {
+        println!("Nothing to join the segments of {} with", self.filename);
+      }
+      return Ok(first.into());
+    };
+
+    let (viddir, filename) = (&self.viddir, &self.filename);
+    let extension = self.container.extension();
+    let outfile = in_viddir(viddir, format!("{filename}_final.{extension}"));
+
+    let (chapters, chapterext) = self.chapter_file(merger);
+    let chapterfile = if chapters.is_empty() {
+      println!("No events during recording, only merging the title");
+      None
+    } else {
+      let chapterfile = in_viddir(viddir, format!("{filename}.{chapterext}"));
+      fs::write(&chapterfile, chapters)?;
+      Some(chapterfile)
+    };
+    let mut intermediate = segments.clone();
+    intermediate.extend(chapterfile.clone());
+
+    let mut merge = match merger {
+      Merger::Mkvmerge(command) => {
+        mkvmerge(command, &self.title, chapterfile, &segments, &outfile)
+      }
+      Merger::Ffmpeg(command) => {
+        let input = if segments.len() > 1 {
+          let concatfile = in_viddir(viddir, format!("{filename}.concat"));
+          fs::write(&concatfile, concat_list(&segments))?;
+          intermediate.push(concatfile.clone());
+          Input::Concat(concatfile)
+        } else {
+          Input::File(first.clone())
+        };
+        ffmpeg(command, input, chapterfile, &outfile)
+      }
+    };
+
+    merge.stdout(Stdio::null());
+    if let Err(e) = run(&mut merge, MERGE_TIMEOUT).await {
+      return Err(io::Error::other(format!(
+        "Merge failed, keeping intermediate files: {e}"
+      )));
+    }
+
+    for file in &intermediate {
+      remove_file(file)?;
+    }
+    Ok(outfile.into())
+  }
+}
+
+/// The file of segment `idx` of the recording `filename`
+pub fn segment_file(
+  viddir: &str,
+  filename: &str,
+  container: Container,
+  idx: usize,
+) -> String {
+  let extension = container.extension();
+  let name = match idx {
+    0 => format!("{filename}.{extension}"),
+    _ => format!("{filename}_part{}.{extension}", idx + 1),
+  };
+  in_viddir(viddir, name)
+}
+
+/// The path of the file `name` in `viddir`. Joined like the entries of
+/// `read_dir`, so the paths match those found by `Retention`.
+fn in_viddir(viddir: &str, name: String) -> String {
+  Path::new(viddir).join(name).to_string_lossy().to_string()
+}
+
+fn mkvmerge(
+  command: &str,
+  title: &str,
+  chapterfile: Option<String>,
+  segments: &[String],
+  outfile: &str,
+) -> Command {
+  let mut merge = Command::new(command);
+  merge.args(["--title", title]);
+  if let Some(chapterfile) = chapterfile {
+    merge.args(["--chapters", &chapterfile]);
+  }
+
+  merge.args(["-o", outfile]);
+  // Appends the following segments to the first
+  for (idx, segment) in segments.iter().enumerate() {
+    if idx > 0 {
+      merge.arg("+");
+    }
+    merge.arg(segment);
+  }
+  merge
+}
+
+/// The video input of ffmpeg
+enum Input {
+  File(String),
+  /// A list of files for the concat demuxer
+  Concat(String),
+}
+
+/// ffmpeg copying `input` with the FFMETADATA `metafile` into `outfile`
+fn ffmpeg(
+  command: &str,
+  input: Input,
+  metafile: Option<String>,
+  outfile: &str,
+) -> Command {
+  let mut merge = Command::new(command);
+  merge.arg("-y");
+  match input {
+    Input::File(file) => merge.args(["-i", &file]),
+    Input::Concat(list) => {
+      merge.args(["-f", "concat", "-safe", "0", "-i", &list])
+    }
+  };
+
+  if let Some(metafile) = metafile {
+    merge.args(["-i", &metafile, "-map_metadata", "1", "-map_chapters", "1"]);
+  }
+  merge.args(["-map", "0", "-c", "copy", outfile]);
+  merge
+}
+
+/// The file list for ffmpeg's concat demuxer. It resolves relative paths
+/// from the directory of the list, which is the one of the `files`.
+fn concat_list(files: &[String]) -> String {
+  files
+    .iter()
+    .filter_map(|f| Path::new(f).file_name())
+    .map(|f| {
+      let f = f.to_string_lossy().replace('\'', "'\\''");
+      format!("file '{f}'\n")
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, time::Instant};
+
+  use chrono::TimeDelta;
+
+  use super::*;
+  use crate::{events::Zone, recorder::Activity};
+
+  #[test]
+  fn ffmpeg_args() {
+    let merge = ffmpeg(
+      "ffmpeg",
+      Input::Concat("list".to_string()),
+      Some("meta".to_string()),
+      "out.mp4",
+    );
+    let args: Vec<_> = merge
+      .as_std()
+      .get_args()
+      .map(|a| a.to_string_lossy())
+      .collect();
+    assert_eq!(
+      args.join(" "),
+      "-y -f concat -safe 0 -i list -i meta -map_metadata 1 -map_chapters 1 \
+       -map 0 -c copy out.mp4"
+    );
+
+    assert_eq!(
+      concat_list(&["dir/a.mp4".to_string(), "dir/it's.mp4".to_string()]),
+      "file 'a.mp4'\nfile 'it'\\''s.mp4'\n"
+    );
+  }
+
+  #[test]
+  fn segment_files() {
+    let file = |viddir, idx| segment_file(viddir, "rec", Container::Mp4, idx);
+    assert_eq!(file("videos", 0), "videos/rec.mp4");
+    // Like the paths `read_dir` returns for `Retention`
+    assert_eq!(file("videos/", 1), "videos/rec_part2.mp4");
+  }
+
+  /// A dungeon recording into `dir`, with a segment each of `segments`
+  fn finished(dir: &Path, segments: Vec<Segment>) -> Finished {
+    let start = segments[0].start;
+    let zone = Zone {
+      instance_id: 2652,
+      name: "The Stonevault".to_string(),
+      difficulty_id: 2,
+    };
+    let recording = Recording::new(
+      start,
+      Instant::now(),
+      "rec".to_string(),
+      Activity::Dungeon(zone),
+    );
+
+    Finished {
+      viddir: dir.to_string_lossy().to_string(),
+      filename: "rec".to_string(),
+      container: Container::Matroska,
+      segments: segments.into_iter().enumerate().collect(),
+      recording,
+      title: "Heroic The Stonevault".to_string(),
+      end: start + TimeDelta::seconds(60),
+      chapter_format: ChapterFormat::Simple,
+      chapter_language: "eng".to_string(),
+    }
+  }
+
+  #[test]
+  fn container_chosen_by_recorder() {
+    let dir =
+      env::temp_dir().join(format!("progrs-finished-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("rec.mp4"), "video").unwrap();
+
+    let start = NaiveDateTime::default();
+    let mut finished = finished(&dir, vec![Segment { start, end: None }]);
+    finished.detect_container();
+    assert_eq!(finished.container, Container::Mp4);
+
+    let mergers = [
+      Merger::Mkvmerge("mkvmerge".to_string()),
+      Merger::Ffmpeg("ffmpeg".to_string()),
+    ];
+    let merger = mergers.iter().find(|m| m.handles(finished.container));
+    assert_eq!(merger, Some(&mergers[1]));
+    assert_eq!(finished.chapter_file(&mergers[1]).1, "ffmeta");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn unwritten_segment() {
+    let dir =
+      env::temp_dir().join(format!("progrs-unwritten-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // The recorder died after 10s without writing anything, the restarted
+    // one recorded from 15s on
+    fs::write(dir.join("rec_part2.mkv"), "video").unwrap();
+
+    let start = NaiveDateTime::default();
+    let segments = vec![
+      Segment {
+        start,
+        end: Some(start + TimeDelta::seconds(10)),
+      },
+      Segment {
+        start: start + TimeDelta::seconds(15),
+        end: None,
+      },
+    ];
+    let mut finished = finished(&dir, segments);
+    finished
+      .recording
+      .add_death(start + TimeDelta::seconds(20), "Foo".to_string());
+
+    finished.detect_container();
+    assert_eq!(finished.container, Container::Matroska);
+    finished.drop_unwritten();
+    assert_eq!(finished.segments.len(), 1);
+    assert_eq!(finished.segments[0].0, 1);
+
+    // The death is 5s into the video, not 15s
+    assert_eq!(finished.metadata().events[0].video_ms, 5000);
+    let mkvmerge = Merger::Mkvmerge("mkvmerge".to_string());
+    assert_eq!(
+      finished.chapter_file(&mkvmerge),
+      (
+        "CHAPTER01=00:00:05.000\nCHAPTER01NAME=Death: Foo\n".to_string(),
+        "txt"
+      )
+    );
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,120 @@
+use std::{future::pending, time::Duration};
+
+use tokio::time::{sleep_until, Instant};
+
+use crate::events::{Event, InstanceType};
+
+/// Keeps recording for a while after an activity ended
+#[derive(Debug, Default)]
+pub struct PostRoll {
+  /// When to stop the recording
+  until: Option<Instant>,
+}
+
+impl PostRoll {
+  /// Stops the recording `seconds` from now
+  pub fn start(&mut self, seconds: u32) {
+    self.until = Some(Instant::now() + Duration::from_secs(seconds.into()));
+  }
+
+  pub fn is_running(&self) -> bool {
+    self.until.is_some()
+  }
+
+  /// Resolves once the post-roll is over and the recording has to be
+  /// stopped. Never resolves if no post-roll is running.
+  pub async fn expired(&mut self) {
+    let Some(until) = self.until else {
+      return pending().await;
+    };
+
+    sleep_until(until).await;
+    self.until = None;
+  }
+
+  /// Ends the post-roll without stopping the recording, e.g. when it is
+  /// stopped anyways
+  pub fn cancel(&mut self) {
+    self.until = None;
+  }
+
+  /// Ends the post-roll early if `event` starts the next activity. Returns
+  /// whether the recording has to be stopped now.
+  pub fn cut_short(
+    &mut self,
+    event: &Event,
+    instance_types: &[InstanceType],
+  ) -> bool {
+    if !self.is_running() || !event.starts_activity(instance_types) {
+      return false;
+    }
+
+    self.until = None;
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::NaiveDateTime;
+  use tokio::time::timeout;
+
+  use super::*;
+  use crate::events::{Encounter, Zone};
+
+  fn encounter_start() -> Event {
+    Event::EncounterStart(NaiveDateTime::default(), Encounter::raid_fixture())
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn expires() {
+    let mut post_roll = PostRoll::default();
+    let start = Instant::now();
+    post_roll.start(5);
+    assert!(post_roll.is_running());
+
+    let early = timeout(Duration::from_secs(4), post_roll.expired()).await;
+    assert!(early.is_err());
+    assert!(post_roll.is_running());
+
+    post_roll.expired().await;
+    assert_eq!(start.elapsed(), Duration::from_secs(5));
+    assert!(!post_roll.is_running());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn next_activity() {
+    let mut post_roll = PostRoll::default();
+    // Nothing to cut short
+    assert!(!post_roll.cut_short(&encounter_start(), &[]));
+
+    post_roll.start(5);
+    assert!(!post_roll.cut_short(&Event::ReplayFinished, &[]));
+    assert!(post_roll.cut_short(&encounter_start(), &[]));
+    assert!(!post_roll.is_running());
+
+    // Zones only if they are recorded
+    let dungeon = Event::ZoneChange(
+      NaiveDateTime::default(),
+      Zone {
+        instance_id: 2660,
+        name: "Ara-Kara, City of Echoes".to_string(),
+        difficulty_id: 2,
+      },
+    );
+    post_roll.start(5);
+    assert!(!post_roll.cut_short(&dungeon, &[]));
+    assert!(post_roll.cut_short(&dungeon, &[InstanceType::Dungeon]));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn ctrlc() {
+    let mut post_roll = PostRoll::default();
+    post_roll.start(5);
+    post_roll.cancel();
+    assert!(!post_roll.is_running());
+
+    let expired = timeout(Duration::from_secs(60), post_roll.expired()).await;
+    assert!(expired.is_err());
+  }
+}
@@ -0,0 +1,215 @@
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+  process::ExitStatus,
+  time::Duration,
+};
+
+use nix::{
+  sys::signal::{kill, Signal},
+  unistd::Pid,
+};
+use tokio::{
+  fs::File,
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+  process::{Child, ChildStderr, Command},
+  time::timeout,
+};
+
+/// How long a process gets to exit after SIGTERM, before it is killed
+const TERM_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many recorder logs are kept
+const LOG_FILES: usize = 50;
+/// Recorder logs are cut off at this size
+const MAX_LOG_SIZE: usize = 10 << 20;
+
+/// Sends `signal` to `child`, unless it exited already
+pub fn signal(child: &Child, signal: Signal) -> io::Result<()> {
+  match child.id() {
+    Some(id) => signal_pid(id, signal),
+    None => Ok(()),
+  }
+}
+
+/// Sends `signal` to the process with the ID `id`
+pub fn signal_pid(id: u32, signal: Signal) -> io::Result<()> {
+  let pid = Pid::from_raw(i32::try_from(id).expect("Pid conversion to i32"));
+  Ok(kill(pid, signal)?)
+}
+
+/// Waits for `child` to exit. If that takes longer than `grace`, it is sent
+/// SIGTERM, and killed if it does not exit after that either.
+pub async fn wait_or_kill(
+  child: &mut Child,
+  grace: Duration,
+) -> io::Result<ExitStatus> {
+  if let Ok(status) = timeout(grace, child.wait()).await {
+    return status;
+  }
+
+  println!("Process did not exit within {}s, terminating", grace.as_secs());
+  signal(child, Signal::SIGTERM)?;
+  if let Ok(status) = timeout(TERM_TIMEOUT, child.wait()).await {
+    return status;
+  }
+
+  println!("Process did not terminate, killing it");
+  child.kill().await?;
+  child.wait().await
+}
+
+/// Runs `command` to completion, giving it at most `grace`, see
+/// `wait_or_kill`. Fails if it does not exit successfully.
+pub async fn run(command: &mut Command, grace: Duration) -> io::Result<()> {
+  let mut child = command.spawn()?;
+  let status = wait_or_kill(&mut child, grace).await?;
+
+  if !status.success() {
+    return Err(io::Error::other(format!("exited with status {status}")));
+  }
+  Ok(())
+}
+
+/// Writes the lines of `stderr` not containing any of `filter` into
+/// `logfile`, or prints them without one or if it can't be created. Ends when
+/// `stderr` is closed, i.e. usually when the process exits.
+pub async fn log_stderr(
+  stderr: ChildStderr,
+  logfile: Option<PathBuf>,
+  filter: Vec<String>,
+) {
+  let mut reader = BufReader::new(stderr);
+  let mut buf = vec![];
+  let mut log = None;
+  if let Some(logfile) = &logfile {
+    match File::create(logfile).await {
+      Ok(f) => log = Some(f),
+      Err(e) => println!("Could not create {}: {e}", logfile.display()),
+    }
+  }
+  let mut written = 0;
+
+  // Keep reading after invalid UTF-8, write errors or a full log until EOF,
+  // a full or closed pipe blocks or kills the process
+  loop {
+    buf.clear();
+    match reader.read_until(b'\n', &mut buf).await {
+      Ok(0) => break,
+      Ok(_) => {}
+      Err(e) => {
+        println!("Could not read recorder output: {e}");
+        break;
+      }
+    }
+    let line = String::from_utf8_lossy(&buf)
+      .trim_end_matches(['\r', '\n'])
+      .to_string();
+
+    if filter.iter().any(|f| line.contains(f)) {
+      continue;
+    }
+
+    let Some(file) = log.as_mut() else {
+      println!("'{line}'");
+      continue;
+    };
+    if written > MAX_LOG_SIZE {
+      continue;
+    }
+
+    written += line.len() + 1;
+    let line = if written > MAX_LOG_SIZE {
+      "Log is too large, cut off here\n".to_string()
+    } else {
+      line + "\n"
+    };
+    if let Err(e) = file.write_all(line.as_bytes()).await {
+      println!("Could not write recorder log: {e}");
+      log = None;
+    }
+  }
+
+  if let Some(mut file) = log {
+    let _ = file.flush().await;
+  }
+}
+
+/// Removes the oldest log files in `logdir`, making room for a new one
+pub fn rotate_logs(logdir: &Path) -> io::Result<()> {
+  fs::create_dir_all(logdir)?;
+
+  let mut logs = vec![];
+  for entry in fs::read_dir(logdir)? {
+    let entry = entry?;
+    if entry.path().extension().is_some_and(|e| e == "log") {
+      logs.push((entry.metadata()?.modified()?, entry.path()));
+    }
+  }
+
+  logs.sort();
+  let excess = (logs.len() + 1).saturating_sub(LOG_FILES);
+  for (_, log) in logs.into_iter().take(excess) {
+    fs::remove_file(log)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, process::Stdio};
+
+  use super::*;
+
+  #[tokio::test]
+  async fn stderr_into_log() {
+    let dir =
+      env::temp_dir().join(format!("progrs-stderr-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let logfile = dir.join("recording.log");
+
+    let mut child = Command::new("/bin/sh")
+      .args([
+        "-c",
+        "echo one >&2; printf 'bad \\377 byte\\n' >&2; \
+         echo 'update fps 60' >&2; echo two >&2",
+      ])
+      .stderr(Stdio::piped())
+      .spawn()
+      .unwrap();
+    let stderr = child.stderr.take().unwrap();
+    log_stderr(stderr, Some(logfile.clone()), vec!["fps".to_string()]).await;
+    child.wait().await.unwrap();
+
+    assert_eq!(
+      fs::read_to_string(&logfile).unwrap(),
+      "one\nbad \u{fffd} byte\ntwo\n"
+    );
+
+    for idx in 0..LOG_FILES + 3 {
+      fs::write(dir.join(format!("{idx}.log")), "").unwrap();
+    }
+    rotate_logs(&dir).unwrap();
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), LOG_FILES - 1);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn escalate() {
+    // Ignores SIGTERM, so it has to be killed
+    let mut command = Command::new("/bin/sh");
+    command.args(["-c", "trap '' TERM; sleep 10"]);
+
+    let e = run(&mut command, Duration::from_millis(100)).await.unwrap_err();
+    assert!(e.to_string().contains("signal: 9"), "{e}");
+  }
+
+  #[tokio::test]
+  async fn success() {
+    run(&mut Command::new("/bin/true"), Duration::from_secs(1))
+      .await
+      .unwrap();
+    assert!(run(&mut Command::new("/bin/false"), Duration::from_secs(1))
+      .await
+      .is_err());
+  }
+}
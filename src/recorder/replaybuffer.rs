@@ -0,0 +1,323 @@
+use std::{
+  collections::VecDeque,
+  fs, io,
+  path::{Path, PathBuf},
+  process::Stdio,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use chrono::Local;
+use futures_util::future::BoxFuture;
+use nix::sys::signal::Signal;
+use tokio::{
+  io::{AsyncBufReadExt, BufReader},
+  process::{Child, Command},
+  sync::oneshot,
+  time::timeout,
+};
+
+use super::{
+  backend::{Health, RecorderBackend, GSR_STDERR_FILTER},
+  process::{log_stderr, rotate_logs, run, signal, signal_pid},
+};
+
+/// How long to wait for gpu-screen-recorder to save the replay
+const SAVE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long ffmpeg gets to trim the replay
+const TRIM_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Waiting for the path of a saved replay, in the order they were requested
+type Saves = Arc<Mutex<VecDeque<oneshot::Sender<String>>>>;
+
+/// gpu-screen-recorder running continuously in replay mode (`-r`). Stopping a
+/// recording saves the replay buffer with SIGUSR1 and trims it to the time
+/// since the start plus the pre-roll.
+pub struct ReplayBufferBackend {
+  command: String,
+  args: Vec<String>,
+  /// Length of the replay buffer
+  seconds: u32,
+  pre_roll: Duration,
+  /// The directory gpu-screen-recorder saves the replays in
+  dir: String,
+  /// ffmpeg, to trim the replays. Untrimmed if `None`.
+  ffmpeg: Option<String>,
+  /// The buffering recorder and when it was started
+  process: Option<(Child, Instant)>,
+  saves: Saves,
+  /// The file being recorded into, the time recording started and how much
+  /// video to keep before that
+  recording: Option<(String, Instant, Duration)>,
+  /// Stderr lines containing any of these are not logged
+  pub stderr_filter: Vec<String>,
+  /// Where the stderr of each buffering recorder is logged, printed if `None`
+  pub logdir: Option<PathBuf>,
+}
+
+impl ReplayBufferBackend {
+  pub fn new(
+    command: String,
+    args: Vec<String>,
+    seconds: u32,
+    pre_roll_seconds: u32,
+    dir: String,
+    ffmpeg: Option<String>,
+  ) -> Self {
+    Self {
+      command,
+      args,
+      seconds,
+      pre_roll: Duration::from_secs(pre_roll_seconds.into()),
+      dir,
+      ffmpeg,
+      process: None,
+      saves: Arc::new(Mutex::new(VecDeque::new())),
+      recording: None,
+      stderr_filter: GSR_STDERR_FILTER.map(String::from).to_vec(),
+      logdir: None,
+    }
+  }
+
+  /// Starts buffering right away, so the first activity has its pre-roll
+  pub fn start_buffering(&mut self) {
+    if let Err(e) = self.ensure_running() {
+      println!("Could not start replay buffer: {e}");
+    }
+  }
+
+  /// Starts the buffering recorder, unless it is running already
+  fn ensure_running(&mut self) -> io::Result<()> {
+    if self.health() == Health::Running {
+      return Ok(());
+    }
+
+    let mut process = Command::new(&self.command)
+      .args(&self.args)
+      .args(["-r", &self.seconds.to_string(), "-o", &self.dir])
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .stdin(Stdio::null())
+      .spawn()?;
+    println!("Replay buffer {} started", process.id().unwrap_or_default());
+
+    // The buffer runs across recordings, so it gets a log of its own
+    let logfile = self.logdir.as_ref().map(|dir| {
+      if let Err(e) = rotate_logs(dir) {
+        println!("Could not remove old recorder logs: {e}");
+      }
+      let name = Local::now().format("replay_buffer_%Y%m%d_%H%M%S.log");
+      dir.join(name.to_string())
+    });
+    tokio::spawn(log_stderr(
+      process.stderr.take().expect("Stderr is piped"),
+      logfile,
+      self.stderr_filter.clone(),
+    ));
+
+    // gpu-screen-recorder prints the path of each saved replay
+    let stdout = process.stdout.take().expect("Stdout is piped");
+    let mut reader = BufReader::new(stdout);
+    let saves = self.saves.clone();
+    tokio::spawn(async move {
+      let mut buf = vec![];
+      // Invalid UTF-8 must not end this, later saves would time out
+      while reader.read_until(b'\n', &mut buf).await.is_ok_and(|n| n > 0) {
+        let line = String::from_utf8_lossy(&buf)
+          .trim_end_matches(['\r', '\n'])
+          .to_string();
+        buf.clear();
+        if !Path::new(&line).is_file() {
+          continue;
+        }
+
+        match saves.lock().expect("Saves lock").pop_front() {
+          Some(save) => {
+            let _ = save.send(line);
+          }
+          None => println!("Replay {line} saved, but nobody asked for it"),
+        }
+      }
+    });
+
+    self.process = Some((process, Instant::now()));
+    Ok(())
+  }
+}
+
+impl RecorderBackend for ReplayBufferBackend {
+  fn start(&mut self, outfile: &str) -> io::Result<()> {
+    if self.recording.is_some() {
+      return Err(io::Error::other("Recorder is already running"));
+    }
+
+    self.ensure_running()?;
+    let (_, buffering_since) = self.process.as_ref().expect("Just started");
+    let pre_roll = self.pre_roll.min(buffering_since.elapsed());
+    self.recording = Some((outfile.to_string(), Instant::now(), pre_roll));
+    Ok(())
+  }
+
+  fn video_start(&self) -> Option<Instant> {
+    let (_, started, pre_roll) = self.recording.as_ref()?;
+    started.checked_sub(*pre_roll)
+  }
+
+  fn stop(&mut self) -> BoxFuture<'static, io::Result<()>> {
+    let recording = self.recording.take();
+    let process = self.process.as_ref().and_then(|(p, _)| p.id());
+    let saves = self.saves.clone();
+    let buffer = Duration::from_secs(self.seconds.into());
+    let ffmpeg = self.ffmpeg.clone();
+
+    Box::pin(async move {
+      let (Some((outfile, started, pre_roll)), Some(pid)) = (recording, process)
+      else {
+        return Err(io::Error::other("Recorder is not running"));
+      };
+
+      let keep = started.elapsed() + pre_roll;
+      if keep > buffer {
+        println!(
+          "Recording took longer than the replay buffer of {}s, the start is \
+           missing",
+          buffer.as_secs()
+        );
+      }
+
+      let (tx, rx) = oneshot::channel();
+      saves.lock().expect("Saves lock").push_back(tx);
+      println!("Saving replay buffer {pid}");
+      signal_pid(pid, Signal::SIGUSR1)?;
+
+      let replay = timeout(SAVE_TIMEOUT, rx)
+        .await
+        .map_err(|_| io::Error::other("Saving the replay timed out"))?
+        .map_err(|_| io::Error::other("Replay buffer exited while saving"))?;
+
+      let Some(ffmpeg) = ffmpeg else {
+        println!("No ffmpeg to trim the replay, keeping all of it");
+        return fs::rename(&replay, &outfile);
+      };
+
+      let mut trim = Command::new(ffmpeg);
+      trim
+        .args(trim_args(keep, &replay, &outfile))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+      if let Err(e) = run(&mut trim, TRIM_TIMEOUT).await {
+        return Err(io::Error::other(format!(
+          "Trimming {replay} failed, keeping it: {e}"
+        )));
+      }
+
+      fs::remove_file(&replay)
+    })
+  }
+
+  fn health(&mut self) -> Health {
+    let Some((process, _)) = self.process.as_mut() else {
+      return Health::Stopped("not started".to_string());
+    };
+
+    match process.try_wait() {
+      Ok(None) => Health::Running,
+      Ok(Some(status)) => Health::Stopped(format!("exited with {status}")),
+      Err(e) => Health::Stopped(e.to_string()),
+    }
+  }
+}
+
+impl Drop for ReplayBufferBackend {
+  /// The buffer would keep running after progrs exits otherwise
+  fn drop(&mut self) {
+    if let Some((process, _)) = self.process.as_ref() {
+      let _ = signal(process, Signal::SIGINT);
+    }
+  }
+}
+
+/// ffmpeg arguments copying the last `keep` of `replay` into `outfile`
+fn trim_args(keep: Duration, replay: &str, outfile: &str) -> Vec<String> {
+  [
+    "-y",
+    "-sseof",
+    &format!("-{:.3}", keep.as_secs_f64()),
+    "-i",
+    replay,
+    "-map",
+    "0",
+    "-c",
+    "copy",
+    outfile,
+  ]
+  .map(String::from)
+  .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+
+  #[test]
+  fn trim() {
+    assert_eq!(
+      trim_args(Duration::from_millis(12_500), "in.mkv", "out.mkv").join(" "),
+      "-y -sseof -12.500 -i in.mkv -map 0 -c copy out.mkv"
+    );
+  }
+
+  #[tokio::test]
+  async fn save_replay() {
+    let dir =
+      env::temp_dir().join(format!("progrs-replay-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let outfile = dir.join("out.mkv").to_string_lossy().to_string();
+
+    // Saves a replay into the `-o` directory on SIGUSR1, like
+    // gpu-screen-recorder. $0 .. $3 are `-r 30 -o dir`.
+    let script = "echo 'update fps: 60' >&2; echo buffering >&2; \
+                  printf 'bad \\377 byte\\n'; \
+                  trap 'echo video > \"$3/replay.mkv\"; \
+                  echo \"$3/replay.mkv\"' USR1; \
+                  while :; do sleep 0.05; done";
+    let mut backend = ReplayBufferBackend::new(
+      "/bin/sh".to_string(),
+      vec!["-c".to_string(), script.to_string()],
+      30,
+      10,
+      dir.to_string_lossy().to_string(),
+      None,
+    );
+    let logdir = dir.join("logs");
+    backend.logdir = Some(logdir.clone());
+    backend.start_buffering();
+    assert_eq!(backend.health(), Health::Running);
+
+    backend.start(&outfile).unwrap();
+    assert!(backend.start(&outfile).is_err());
+    // Give the shell time to set up the trap
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    backend.stop().await.unwrap();
+    assert_eq!(fs::read_to_string(&outfile).unwrap(), "video\n");
+    assert!(!dir.join("replay.mkv").exists());
+
+    // The buffer keeps running for the next recording
+    assert_eq!(backend.health(), Health::Running);
+    assert!(backend.stop().await.is_err());
+
+    // The log is flushed once the buffer exited
+    drop(backend);
+    let log = fs::read_dir(&logdir).unwrap().next().unwrap().unwrap().path();
+    for _ in 0..100 {
+      if fs::read_to_string(&log).unwrap() == "buffering\n" {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(fs::read_to_string(&log).unwrap(), "buffering\n");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,312 @@
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+use nix::sys::statvfs::statvfs;
+
+use crate::{config::ProgrsConfig, index::Index};
+
+/// Files modified more recently are left alone, they might still be recorded
+/// or post-processed
+const RECENT: Duration = Duration::from_secs(600);
+/// The extensions of recordings, see `Container`
+const VIDEO_EXTENSIONS: &[&str] = &["mkv", "mp4", "mov"];
+
+/// Deletes old recordings according to the rules in `[retention]`
+pub struct Retention {
+  viddir: PathBuf,
+  /// Deleted recordings are removed from the index, too
+  index: Option<PathBuf>,
+  max_size: Option<u64>,
+  max_age: Option<Duration>,
+  keep_kills: bool,
+  keep_timed_keys: bool,
+  favorites: Vec<String>,
+  /// Bytes needed in `viddir` to start a recording
+  pub min_free: u64,
+  emergency_cleanup: bool,
+}
+
+/// A recording in `viddir`
+struct Stored {
+  video: PathBuf,
+  /// The video and its sidecar, if any
+  files: Vec<PathBuf>,
+  size: u64,
+  modified: SystemTime,
+}
+
+impl Retention {
+  pub fn new(conf: &ProgrsConfig, index: Option<PathBuf>) -> Self {
+    let retention = &conf.retention;
+
+    Self {
+      viddir: conf.viddir.clone().into(),
+      index,
+      max_size: retention.max_size_gib.map(|gib| gib << 30),
+      max_age: retention
+        .max_age_days
+        .map(|days| Duration::from_secs(u64::from(days) * 24 * 60 * 60)),
+      keep_kills: retention.keep_kills,
+      keep_timed_keys: retention.keep_timed_keys,
+      favorites: retention.favorites.clone(),
+      min_free: retention.min_free_gib << 30,
+      emergency_cleanup: retention.emergency_cleanup,
+    }
+  }
+
+  /// Deletes the recordings that are too old, and the oldest ones while the
+  /// recordings take more than the maximum size. Returns the deleted videos.
+  pub fn enforce(&self) -> io::Result<Vec<PathBuf>> {
+    if self.max_size.is_none() && self.max_age.is_none() {
+      return Ok(vec![]);
+    }
+
+    let deleted = self.delete_while(|age, total, _| {
+      self.max_age.is_some_and(|max| age > max)
+        || self.max_size.is_some_and(|max| total > max)
+    })?;
+    Ok(deleted.0)
+  }
+
+  /// Checks that an activity can be recorded with `free` bytes available in
+  /// `viddir`. With the emergency cleanup, the oldest recordings are deleted
+  /// first if that is less than `min_free`. Returns why not otherwise.
+  pub fn make_room(&self, mut free: u64) -> Result<(), String> {
+    if free < self.min_free && self.emergency_cleanup {
+      let needed = self.min_free - free;
+      println!("Only {} MiB free, deleting old recordings", free >> 20);
+      match self.delete_while(|_, _, freed| freed < needed) {
+        Ok((deleted, freed)) => {
+          for video in deleted {
+            println!("Deleted old recording {}", video.display());
+          }
+          free += freed;
+        }
+        Err(e) => println!("Emergency cleanup failed: {e}"),
+      }
+    }
+
+    if free < self.min_free {
+      return Err(format!(
+        "only {} MiB free in {}, {} MiB needed",
+        free >> 20,
+        self.viddir.display(),
+        self.min_free >> 20
+      ));
+    }
+    Ok(())
+  }
+
+  /// The bytes available to unprivileged users in `viddir`
+  pub fn free_space(&self) -> io::Result<u64> {
+    let stat = statvfs(&self.viddir)?;
+    Ok(stat.blocks_available() * stat.fragment_size())
+  }
+
+  /// Deletes unprotected recordings, oldest first, while `delete` returns
+  /// `true` for them. It gets the age of the recording, the total size of all
+  /// recordings and the bytes freed so far. Returns the deleted videos and the
+  /// bytes freed.
+  fn delete_while(
+    &self,
+    mut delete: impl FnMut(Duration, u64, u64) -> bool,
+  ) -> io::Result<(Vec<PathBuf>, u64)> {
+    let now = SystemTime::now();
+    let recordings = self.recordings()?;
+    let mut total: u64 = recordings.iter().map(|r| r.size).sum();
+    let mut freed = 0;
+    let mut deleted = vec![];
+
+    for recording in recordings {
+      let age = now.duration_since(recording.modified).unwrap_or_default();
+      if age < RECENT || self.is_protected(&recording) {
+        continue;
+      }
+      if !delete(age, total, freed) {
+        continue;
+      }
+
+      for file in &recording.files {
+        fs::remove_file(file)?;
+      }
+      total -= recording.size;
+      freed += recording.size;
+      self.unindex(&recording.video);
+      deleted.push(recording.video);
+    }
+
+    Ok((deleted, freed))
+  }
+
+  /// The recordings in `viddir`, oldest first
+  fn recordings(&self) -> io::Result<Vec<Stored>> {
+    let mut recordings = vec![];
+
+    for entry in fs::read_dir(&self.viddir)? {
+      let video = entry?.path();
+      let is_video = video
+        .extension()
+        .is_some_and(|e| VIDEO_EXTENSIONS.iter().any(|v| e == *v));
+      if !is_video || !video.is_file() {
+        continue;
+      }
+
+      let metadata = video.metadata()?;
+      let mut recording = Stored {
+        files: vec![video.clone()],
+        size: metadata.len(),
+        modified: metadata.modified()?,
+        video,
+      };
+      let sidecar = recording.video.with_extension("json");
+      if let Ok(metadata) = sidecar.metadata() {
+        recording.size += metadata.len();
+        recording.files.push(sidecar);
+      }
+      recordings.push(recording);
+    }
+
+    recordings.sort_by_key(|r| r.modified);
+    Ok(recordings)
+  }
+
+  /// Whether `recording` is kept regardless of its age and size
+  fn is_protected(&self, recording: &Stored) -> bool {
+    let video = &recording.video;
+    let name = video.file_name().unwrap_or_default().to_string_lossy();
+    let stem = video.file_stem().unwrap_or_default().to_string_lossy();
+    if self.favorites.iter().any(|f| *f == name || *f == stem) {
+      return true;
+    }
+
+    // The outcome is part of the file name, see `Finished::tag`
+    let tagged = |outcome| stem.split('_').any(|part| part == outcome);
+    (self.keep_kills && tagged("kill"))
+      || (self.keep_timed_keys && tagged("timed"))
+  }
+
+  fn unindex(&self, video: &Path) {
+    let Some(index) = &self.index else {
+      return;
+    };
+
+    if let Err(e) = Index::open(index).and_then(|i| i.remove(video)) {
+      println!("Could not remove {} from the index: {e}", video.display());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs::File};
+
+  use super::*;
+
+  /// Creates `name` in `dir` with `size` bytes, modified `days` and an hour
+  /// ago
+  fn create(dir: &Path, name: &str, size: usize, days: u64) {
+    fs::write(dir.join(name), vec![0; size]).unwrap();
+    let modified =
+      SystemTime::now() - Duration::from_secs((days * 24 + 1) * 60 * 60);
+    File::options()
+      .write(true)
+      .open(dir.join(name))
+      .unwrap()
+      .set_modified(modified)
+      .unwrap();
+  }
+
+  #[test]
+  fn oldest_first() {
+    let dir =
+      env::temp_dir().join(format!("progrs-retention-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    create(&dir, "a_Mythic_Boss_kill_final.mkv", 100, 40);
+    create(&dir, "a_Mythic_Boss_kill_final.json", 10, 40);
+    create(&dir, "b_Mythic_Boss_wipe_final.mkv", 100, 40);
+    create(&dir, "b_Mythic_Boss_wipe_final.json", 10, 40);
+    create(&dir, "c_+15_Key_depleted.mkv", 100, 3);
+    create(&dir, "d_+15_Key_timed.mp4", 100, 2);
+    create(&dir, "e_Favorite.mkv", 100, 1);
+    create(&dir, "f_Heroic_Boss_wipe.mkv", 100, 0);
+    create(&dir, "notes.txt", 1000, 50);
+    // Still being recorded
+    fs::write(dir.join("g_Heroic_Boss.mkv"), vec![0; 1000]).unwrap();
+
+    let retention = Retention {
+      viddir: dir.clone(),
+      index: None,
+      max_size: Some(1500),
+      max_age: Some(Duration::from_secs(30 * 24 * 60 * 60)),
+      keep_kills: true,
+      keep_timed_keys: true,
+      favorites: vec!["e_Favorite".to_string()],
+      min_free: 0,
+      emergency_cleanup: false,
+    };
+    let deleted: Vec<_> = retention
+      .enforce()
+      .unwrap()
+      .into_iter()
+      .map(|f| f.file_name().unwrap().to_string_lossy().to_string())
+      .collect();
+
+    // The wipe is too old, then the oldest unprotected recordings go until
+    // the total is below 1500 bytes
+    assert_eq!(
+      deleted,
+      ["b_Mythic_Boss_wipe_final.mkv", "c_+15_Key_depleted.mkv"]
+    );
+    assert!(!dir.join("b_Mythic_Boss_wipe_final.json").exists());
+    assert!(dir.join("a_Mythic_Boss_kill_final.json").exists());
+    assert!(dir.join("notes.txt").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn free_space() {
+    let dir =
+      env::temp_dir().join(format!("progrs-free-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    create(&dir, "a_Mythic_Boss_kill.mkv", 100, 3);
+    create(&dir, "b_Mythic_Boss_wipe.mkv", 100, 2);
+    create(&dir, "c_Mythic_Boss_wipe.mkv", 100, 1);
+
+    let mut retention = Retention {
+      viddir: dir.clone(),
+      index: None,
+      max_size: None,
+      max_age: None,
+      keep_kills: true,
+      keep_timed_keys: false,
+      favorites: vec![],
+      min_free: 1000,
+      emergency_cleanup: false,
+    };
+    assert_eq!(retention.make_room(1000), Ok(()));
+    let skipped = retention.make_room(950).unwrap_err();
+    assert!(skipped.starts_with("only 0 MiB free in"));
+    assert!(dir.join("b_Mythic_Boss_wipe.mkv").exists());
+
+    // The oldest unprotected recording frees enough
+    retention.emergency_cleanup = true;
+    assert_eq!(retention.make_room(950), Ok(()));
+    assert!(dir.join("a_Mythic_Boss_kill.mkv").exists());
+    assert!(!dir.join("b_Mythic_Boss_wipe.mkv").exists());
+    assert!(dir.join("c_Mythic_Boss_wipe.mkv").exists());
+
+    // Deleting everything but the kill is not enough
+    assert!(retention.make_room(750).is_err());
+    assert!(!dir.join("c_Mythic_Boss_wipe.mkv").exists());
+    assert!(dir.join("a_Mythic_Boss_kill.mkv").exists());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
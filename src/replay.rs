@@ -0,0 +1,137 @@
+use std::{
+  fs::File,
+  io::{self, BufRead, BufReader},
+  path::Path,
+};
+
+use chrono::NaiveDateTime;
+use tokio::{
+  sync::mpsc::{self, Receiver, Sender},
+  time::{sleep_until, Instant},
+};
+
+use crate::{
+  events::Event,
+  parser::{LogLine, Parser},
+};
+
+/// Streams the finished log `file` through the parser, line by line. With
+/// `speed`, the time between lines is taken from their timestamps, scaled by
+/// `speed`, otherwise the file is replayed as fast as possible.
+///
+/// Sends `Event::ReplayFinished` after the last line. Like `DirWatcher::at`,
+/// returns a sender to inject events.
+pub fn at(
+  file: &Path,
+  speed: Option<f64>,
+) -> io::Result<(Receiver<Event>, Sender<Event>)> {
+  let (tx, rx): (Sender<Event>, Receiver<Event>) = mpsc::channel(1);
+  let reader = BufReader::new(File::open(file)?);
+
+  println!("Now replaying {}", file.to_string_lossy());
+
+  tokio::spawn(replay(reader, speed, tx.clone()));
+  Ok((rx, tx))
+}
+
+async fn replay<R: BufRead>(
+  mut reader: R,
+  speed: Option<f64>,
+  tx: Sender<Event>,
+) {
+  let parser = Parser::new();
+  let mut line = vec![];
+  let mut start = None;
+
+  loop {
+    line.clear();
+    match reader.read_until(b'\n', &mut line) {
+      Ok(0) => break,
+      Ok(_) => {}
+      Err(e) => {
+        tx.send(Event::IoErr(e)).await.expect("Event channel");
+        return;
+      }
+    }
+
+    if let Some(speed) = speed {
+      pace(&line, speed, &mut start).await;
+    }
+
+    // The parser keeps incomplete lines, the last one might lack the '\n'
+    if !line.ends_with(b"\n") {
+      line.push(b'\n');
+    }
+
+    let mut slice: &[u8] = &line;
+    parser.parse(&mut slice, tx.clone()).await;
+  }
+
+  tx.send(Event::ReplayFinished).await.expect("Event channel");
+}
+
+/// Waits until `line` is due. `start` is the log time and wall clock time of
+/// the first line, set on the first call.
+async fn pace(
+  line: &[u8],
+  speed: f64,
+  start: &mut Option<(NaiveDateTime, Instant)>,
+) {
+  let Ok(time) = LogLine::parse(line).and_then(|l| l.datetime()) else {
+    return;
+  };
+
+  let (logstart, wallstart) = *start.get_or_insert((time, Instant::now()));
+  // Timestamps can jump backwards, e.g. on daylight saving time changes
+  let offset = (time - logstart).to_std().unwrap_or_default();
+
+  sleep_until(wallstart + offset.div_f64(speed)).await;
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  const LOG: &[u8] = b"9/19/2024 20:14:04.0000  ENCOUNTER_START,2902,\
+                       \"Ulgrax the Devourer\",16,20,2657\r\n\
+                       9/19/2024 20:14:06.0000  ENCOUNTER_END,2902,\
+                       \"Ulgrax the Devourer\",16,20,1,2000";
+
+  async fn collect(mut rx: Receiver<Event>) -> Vec<Event> {
+    let mut events = vec![];
+    while let Some(e) = rx.recv().await {
+      let finished = matches!(e, Event::ReplayFinished);
+      events.push(e);
+      if finished {
+        break;
+      }
+    }
+    events
+  }
+
+  #[tokio::test]
+  async fn as_fast_as_possible() {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(replay(LOG, None, tx));
+    let events = collect(rx).await;
+
+    // Unlike a live log read in one go, the finished encounter is not skipped
+    assert_eq!(events.len(), 3);
+    assert!(matches!(events[0], Event::EncounterStart(..)));
+    assert!(matches!(events[1], Event::EncounterEnd(..)));
+    assert!(matches!(events[2], Event::ReplayFinished));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn paced() {
+    let (tx, rx) = mpsc::channel(1);
+    let start = Instant::now();
+    tokio::spawn(replay(LOG, Some(4.0), tx));
+    let events = collect(rx).await;
+
+    assert_eq!(events.len(), 3);
+    assert_eq!(start.elapsed(), Duration::from_millis(500));
+  }
+}